@@ -1,14 +1,17 @@
-use std::mem::MaybeUninit;
+use std::mem::{size_of, MaybeUninit};
 use windows::{
     core::Interface,
     Win32::{
-        Foundation::E_ACCESSDENIED,
+        Foundation::{E_ACCESSDENIED, E_POINTER, RECT},
         Graphics::{
-            Direct3D11::{ID3D11Device, ID3D11Texture2D},
+            Direct3D11::{
+                ID3D11Device, ID3D11DeviceContext, ID3D11Texture2D, D3D11_BOX, D3D11_TEXTURE2D_DESC,
+            },
             Dxgi::{
-                Common::DXGI_FORMAT, IDXGIDevice, IDXGIOutput, IDXGIOutput1, IDXGIOutput5,
-                IDXGIOutputDuplication, DXGI_ERROR_ACCESS_LOST, DXGI_ERROR_WAIT_TIMEOUT,
-                DXGI_OUTDUPL_DESC, DXGI_OUTDUPL_FRAME_INFO,
+                Common::{DXGI_FORMAT, DXGI_FORMAT_B8G8R8A8_UNORM},
+                IDXGIDevice, IDXGIOutput, IDXGIOutput1, IDXGIOutput5, IDXGIOutputDuplication,
+                DXGI_ERROR_ACCESS_LOST, DXGI_ERROR_MORE_DATA, DXGI_ERROR_WAIT_TIMEOUT,
+                DXGI_OUTDUPL_DESC, DXGI_OUTDUPL_FRAME_INFO, DXGI_OUTDUPL_MOVE_RECT,
             },
         },
         UI::HiDpi::{
@@ -20,8 +23,10 @@ use windows::{
 
 #[repr(C)]
 pub struct ScreenDuplicator {
-    /// Interface that does the duplication.
-    output_dupl: IDXGIOutputDuplication,
+    /// Interface that does the duplication. `None` while released.
+    output_dupl: Option<IDXGIOutputDuplication>,
+    /// Description of the display, kept for when the duplication is released.
+    dupl_desc: DXGI_OUTDUPL_DESC,
     /// Represents the output of the GPU.
     dxgi_output: IDXGIOutput,
     /// GPU from which the output is being duplicated.
@@ -30,6 +35,11 @@ pub struct ScreenDuplicator {
     supported_formats: Box<[DXGI_FORMAT]>,
     /// Cached result for the usage of IDXGIOutput5.
     is_dpi_aware: bool,
+    /// Whether `output_dupl` was created with `DuplicateOutput1` which delivers the formats in
+    /// `supported_formats` instead of only 8-bit BGRA.
+    uses_duplicate_output1: bool,
+    /// Format of the last acquired frame.
+    last_frame_format: Option<DXGI_FORMAT>,
 }
 
 impl Drop for ScreenDuplicator {
@@ -57,7 +67,7 @@ impl ScreenDuplicator {
             adapter.EnumOutputs(display_index)?
         };
 
-        let output_dupl = ScreenDuplicator::new_output_duplicator(
+        let (output_dupl, uses_duplicate_output1) = ScreenDuplicator::new_output_duplicator(
             &dxgi_output,
             &dxgi_device,
             &supported_formats,
@@ -65,22 +75,37 @@ impl ScreenDuplicator {
         )?;
 
         Ok(ScreenDuplicator {
-            output_dupl,
+            dupl_desc: output_desc(&output_dupl),
+            output_dupl: Some(output_dupl),
             dxgi_output,
             dxgi_device,
             supported_formats,
             is_dpi_aware,
+            uses_duplicate_output1,
+            last_frame_format: None,
         })
     }
 
+    /// Returns the device that the frames are created on.
+    pub fn d3d11_device(&self) -> Result<ID3D11Device, windows::core::Error> {
+        self.dxgi_device.cast()
+    }
+
     /// Returns a description of the display that is currently being duplicated.
     pub fn desc(&self) -> DXGI_OUTDUPL_DESC {
-        let mut dupl_desc: MaybeUninit<DXGI_OUTDUPL_DESC> = MaybeUninit::uninit();
-        unsafe {
-            // NOTE: `GetDesc` always succeeds if the `IDXGIOutputDuplication` used is valid
-            self.output_dupl.GetDesc(dupl_desc.as_mut_ptr());
-            dupl_desc.assume_init()
-        }
+        self.dupl_desc
+    }
+
+    /// Returns the format of the frames delivered by the duplicator. This is the format of the
+    /// last acquired frame, or the one the duplicator is expected to pick before the first frame.
+    pub fn frame_format(&self) -> DXGI_FORMAT {
+        self.last_frame_format.unwrap_or_else(|| {
+            negotiate_frame_format(
+                self.desc().ModeDesc.Format,
+                &self.supported_formats,
+                self.uses_duplicate_output1,
+            )
+        })
     }
 
     /// Get the next available frame.
@@ -92,16 +117,16 @@ impl ScreenDuplicator {
         &'a mut self,
         timeout_millis: u32,
     ) -> Result<(AcquiredFrame<'a>, DXGI_OUTDUPL_FRAME_INFO), AcquireFrameError> {
+        let output_dupl = match &self.output_dupl {
+            Some(output_dupl) => output_dupl,
+            None => return Err(AcquireFrameError::Unknown),
+        };
         let mut frame_info: MaybeUninit<DXGI_OUTDUPL_FRAME_INFO> = MaybeUninit::uninit();
         let mut resource = None;
 
         // SAFETY: Windows API call
         let result = unsafe {
-            self.output_dupl.AcquireNextFrame(
-                timeout_millis,
-                frame_info.as_mut_ptr(),
-                &mut resource,
-            )
+            output_dupl.AcquireNextFrame(timeout_millis, frame_info.as_mut_ptr(), &mut resource)
         };
 
         match result {
@@ -113,7 +138,12 @@ impl ScreenDuplicator {
                     unsafe { (resource.unwrap_unchecked(), frame_info.assume_init()) };
 
                 // SAFETY: `IDXGIResource` to `ID3D11Texture2D` should never fail.
-                let image = unsafe { resource.cast().unwrap_unchecked() };
+                let image: ID3D11Texture2D = unsafe { resource.cast().unwrap_unchecked() };
+
+                let mut desc = D3D11_TEXTURE2D_DESC::default();
+                // SAFETY: Windows API call
+                unsafe { image.GetDesc(&mut desc) };
+                self.last_frame_format = Some(desc.Format);
 
                 let acquired_image = AcquiredFrame {
                     frame: image,
@@ -138,17 +168,31 @@ impl ScreenDuplicator {
     /// Signals that the current frame is done being processed.
     #[inline]
     fn release_frame(&mut self) -> Result<(), windows::core::Error> {
-        unsafe {
-            self.output_dupl.ReleaseFrame()?;
-            Ok(())
+        if let Some(output_dupl) = &self.output_dupl {
+            unsafe { output_dupl.ReleaseFrame()? };
         }
+        Ok(())
+    }
+
+    /// Returns the `IDXGIOutputDuplication`, which is only missing while released.
+    fn output_dupl(&self) -> Result<&IDXGIOutputDuplication, windows::core::Error> {
+        self.output_dupl
+            .as_ref()
+            .ok_or_else(|| DXGI_ERROR_ACCESS_LOST.into())
+    }
+
+    /// Stops the duplication until `reset_output_duplicator` is called, e.g. while the stream is
+    /// paused. This lets the display go idle and frees the duplication for other programs.
+    pub fn release_output_duplicator(&mut self) {
+        let _ = self.release_frame();
+        self.output_dupl = None;
     }
 
     /// Creates a new IDXGIOutputDuplication. Used when access is lost to the display due to
     /// desktop switching or using full-screen programs.
     #[inline]
     pub fn reset_output_duplicator(&mut self) -> Result<(), windows::core::Error> {
-        let output_dupl = ScreenDuplicator::new_output_duplicator(
+        let (output_dupl, uses_duplicate_output1) = ScreenDuplicator::new_output_duplicator(
             &self.dxgi_output,
             &self.dxgi_device,
             &self.supported_formats,
@@ -156,18 +200,21 @@ impl ScreenDuplicator {
         )?;
 
         // This also frees the old output_dupl if it's not null
-        self.output_dupl = output_dupl;
+        self.dupl_desc = output_desc(&output_dupl);
+        self.output_dupl = Some(output_dupl);
+        self.uses_duplicate_output1 = uses_duplicate_output1;
+        self.last_frame_format = None;
 
         Ok(())
     }
 
-    /// Returns a new output duplicator.
+    /// Returns a new output duplicator and whether it was made with `DuplicateOutput1`.
     fn new_output_duplicator(
         dxgi_output: &IDXGIOutput,
         dxgi_device: &IDXGIDevice,
         supported_formats: &[DXGI_FORMAT],
         is_dpi_aware: bool,
-    ) -> Result<IDXGIOutputDuplication, windows::core::Error> {
+    ) -> Result<(IDXGIOutputDuplication, bool), windows::core::Error> {
         // First test if output_dupl can be made with IDXGIOutput5
         if is_dpi_aware {
             unsafe {
@@ -176,8 +223,8 @@ impl ScreenDuplicator {
                 const RESERVED_FLAG: u32 = 0;
                 let output_dupl =
                     hdr_output.DuplicateOutput1(dxgi_device, RESERVED_FLAG, supported_formats);
-                if output_dupl.is_ok() {
-                    return output_dupl;
+                if let Ok(output_dupl) = output_dupl {
+                    return Ok((output_dupl, true));
                 }
             }
         }
@@ -185,7 +232,7 @@ impl ScreenDuplicator {
         // If either not DPI aware or IDXGIOutput5 failed, fall back to IDXGIOutput1
         unsafe {
             let sdr_output: IDXGIOutput1 = dxgi_output.cast()?;
-            sdr_output.DuplicateOutput(dxgi_device)
+            Ok((sdr_output.DuplicateOutput(dxgi_device)?, false))
         }
     }
 
@@ -209,6 +256,37 @@ impl ScreenDuplicator {
     }
 }
 
+/// Returns the description of the display duplicated by `output_dupl`.
+fn output_desc(output_dupl: &IDXGIOutputDuplication) -> DXGI_OUTDUPL_DESC {
+    let mut dupl_desc: MaybeUninit<DXGI_OUTDUPL_DESC> = MaybeUninit::uninit();
+    unsafe {
+        // NOTE: `GetDesc` always succeeds if the `IDXGIOutputDuplication` used is valid
+        output_dupl.GetDesc(dupl_desc.as_mut_ptr());
+        dupl_desc.assume_init()
+    }
+}
+
+/// Returns the format that the duplicator delivers for a display in `mode_format`.
+/// `DuplicateOutput` always converts to 8-bit BGRA while `DuplicateOutput1` keeps the display
+/// format if it is one of the `supported_formats`.
+fn negotiate_frame_format(
+    mode_format: DXGI_FORMAT,
+    supported_formats: &[DXGI_FORMAT],
+    uses_duplicate_output1: bool,
+) -> DXGI_FORMAT {
+    if !uses_duplicate_output1 {
+        return DXGI_FORMAT_B8G8R8A8_UNORM;
+    }
+    if supported_formats.contains(&mode_format) {
+        mode_format
+    } else {
+        supported_formats
+            .first()
+            .copied()
+            .unwrap_or(DXGI_FORMAT_B8G8R8A8_UNORM)
+    }
+}
+
 /// Result of a successful `ScreenDuplicator::acquire_frame`.
 pub struct AcquiredFrame<'a> {
     frame: ID3D11Texture2D,
@@ -222,12 +300,186 @@ impl<'a> Drop for AcquiredFrame<'a> {
     }
 }
 
+impl<'a> AcquiredFrame<'a> {
+    /// Returns the regions that changed since the previous frame, including the destinations of
+    /// the regions that were moved.
+    pub fn changed_rects(&self) -> Result<Vec<RECT>, windows::core::Error> {
+        let output_dupl = self.duplicator.output_dupl()?;
+        let move_rects: Vec<DXGI_OUTDUPL_MOVE_RECT> =
+            query_frame_metadata(|buffer_size, buffer, required_size| {
+                // SAFETY: Windows API call, `buffer` has room for `buffer_size` bytes
+                unsafe { output_dupl.GetFrameMoveRects(buffer_size, buffer, required_size) }
+            })?;
+
+        let mut rects = self.dirty_rects()?;
+        rects.extend(move_rects.iter().map(|move_rect| move_rect.DestinationRect));
+        Ok(rects)
+    }
+
+    /// Returns the regions that were redrawn since the previous frame.
+    fn dirty_rects(&self) -> Result<Vec<RECT>, windows::core::Error> {
+        let output_dupl = self.duplicator.output_dupl()?;
+        query_frame_metadata(|buffer_size, buffer, required_size| {
+            // SAFETY: Windows API call, `buffer` has room for `buffer_size` bytes
+            unsafe { output_dupl.GetFrameDirtyRects(buffer_size, buffer, required_size) }
+        })
+    }
+}
+
+/// Calls `query` with a bigger buffer until the frame metadata fits in it.
+fn query_frame_metadata<T>(
+    mut query: impl FnMut(u32, *mut T, &mut u32) -> Result<(), windows::core::Error>,
+) -> Result<Vec<T>, windows::core::Error> {
+    let mut items: Vec<T> = Vec::new();
+    loop {
+        let buffer_size = (items.capacity() * size_of::<T>()) as u32;
+        let mut required_size = 0;
+        match query(buffer_size, items.as_mut_ptr(), &mut required_size) {
+            Ok(_) => {
+                let len = item_count::<T>(required_size).min(items.capacity());
+                // SAFETY: `query` initialized `required_size` bytes
+                unsafe { items.set_len(len) };
+                return Ok(items);
+            }
+            // The frame has more items than the buffer can hold
+            Err(e) if e.code() == DXGI_ERROR_MORE_DATA => {
+                items.reserve_exact(item_count::<T>(required_size));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Number of whole `T`s that fit in `size_in_bytes`.
+fn item_count<T>(size_in_bytes: u32) -> usize {
+    size_in_bytes as usize / size_of::<T>()
+}
+
 impl<'a> AsRef<ID3D11Texture2D> for AcquiredFrame<'a> {
     fn as_ref(&self) -> &ID3D11Texture2D {
         &self.frame
     }
 }
 
+/// Copy of an acquired frame that can be used after the frame has been released. Like
+/// `AcquiredFrame` it is handed to the encoder by value through `AsRef<ID3D11Texture2D>`, so
+/// clones share the same texture.
+#[derive(Clone)]
+pub struct FrameCopy {
+    texture: ID3D11Texture2D,
+    context: ID3D11DeviceContext,
+    width: u32,
+    height: u32,
+}
+
+// SAFETY: The texture and the immediate context come from the device of the `ScreenDuplicator`
+// that is sent to the encoder thread along with it. `FrameCopy` is not `Sync`, and it and its
+// clones are only used by the input task that owns it, so the immediate context is never used from
+// two threads at once.
+unsafe impl Send for FrameCopy {}
+
+impl FrameCopy {
+    /// Creates a texture with the same description as `frame` and copies `frame` into it.
+    pub fn new(frame: &ID3D11Texture2D) -> Result<FrameCopy, windows::core::Error> {
+        // SAFETY: Windows API calls
+        let (texture, context, desc) = unsafe {
+            let mut desc = D3D11_TEXTURE2D_DESC::default();
+            frame.GetDesc(&mut desc);
+            // Duplicated frames can be flagged as shared which is not needed here
+            desc.MiscFlags = Default::default();
+
+            let mut device = None;
+            frame.GetDevice(&mut device);
+            let device = device.ok_or_else(|| windows::core::Error::from(E_POINTER))?;
+
+            let texture = device.CreateTexture2D(&desc, None)?;
+
+            let mut context = None;
+            device.GetImmediateContext(&mut context);
+            let context = context.ok_or_else(|| windows::core::Error::from(E_POINTER))?;
+
+            (texture, context, desc)
+        };
+
+        let frame_copy = FrameCopy {
+            texture,
+            context,
+            width: desc.Width,
+            height: desc.Height,
+        };
+        frame_copy.copy_from(frame);
+        Ok(frame_copy)
+    }
+
+    /// Copies `frame` into the texture. Returns false if the dimensions of `frame` do not match
+    /// the texture.
+    pub fn copy_from(&self, frame: &ID3D11Texture2D) -> bool {
+        if !self.matches_size(frame) {
+            return false;
+        }
+        // SAFETY: Windows API call
+        unsafe { self.context.CopyResource(&self.texture, frame) };
+        true
+    }
+
+    /// Copies only the `rects` of `frame` into the texture. Returns false if the dimensions of
+    /// `frame` do not match the texture.
+    pub fn copy_rects_from(&self, frame: &ID3D11Texture2D, rects: &[RECT]) -> bool {
+        if !self.matches_size(frame) {
+            return false;
+        }
+        for rect in rects {
+            if let Some(src_box) = copy_box(rect, self.width, self.height) {
+                // SAFETY: Windows API call, `src_box` is within both textures
+                unsafe {
+                    self.context.CopySubresourceRegion(
+                        &self.texture,
+                        0,
+                        src_box.left,
+                        src_box.top,
+                        0,
+                        frame,
+                        0,
+                        Some(&src_box),
+                    );
+                }
+            }
+        }
+        true
+    }
+
+    fn matches_size(&self, frame: &ID3D11Texture2D) -> bool {
+        let mut desc = D3D11_TEXTURE2D_DESC::default();
+        // SAFETY: Windows API call
+        unsafe { frame.GetDesc(&mut desc) };
+        desc.Width == self.width && desc.Height == self.height
+    }
+}
+
+/// Clamps `rect` to a `width` by `height` texture. Returns `None` if nothing is left to copy.
+fn copy_box(rect: &RECT, width: u32, height: u32) -> Option<D3D11_BOX> {
+    let clamp = |value: i32, max: u32| value.max(0).min(max.min(i32::MAX as u32) as i32) as u32;
+    let (left, right) = (clamp(rect.left, width), clamp(rect.right, width));
+    let (top, bottom) = (clamp(rect.top, height), clamp(rect.bottom, height));
+    if left >= right || top >= bottom {
+        return None;
+    }
+    Some(D3D11_BOX {
+        left,
+        top,
+        front: 0,
+        right,
+        bottom,
+        back: 1,
+    })
+}
+
+impl AsRef<ID3D11Texture2D> for FrameCopy {
+    fn as_ref(&self) -> &ID3D11Texture2D {
+        &self.texture
+    }
+}
+
 /// Errors that `ScreenDuplicator::acquire_frame` can return.
 #[derive(Debug)]
 pub enum AcquireFrameError {
@@ -239,7 +491,7 @@ pub enum AcquireFrameError {
 mod tests {
     use super::*;
     use windows::Win32::Graphics::Dxgi::Common::{
-        DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_R10G10B10A2_UNORM, DXGI_FORMAT_R8G8B8A8_UNORM,
+        DXGI_FORMAT_R10G10B10A2_UNORM, DXGI_FORMAT_R16G16B16A16_FLOAT, DXGI_FORMAT_R8G8B8A8_UNORM,
     };
 
     #[test]
@@ -259,6 +511,100 @@ mod tests {
         dbg!(desc);
     }
 
+    #[test]
+    fn frame_format_negotiation() {
+        let supported = [DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_R10G10B10A2_UNORM];
+
+        assert_eq!(
+            negotiate_frame_format(DXGI_FORMAT_R10G10B10A2_UNORM, &supported, true),
+            DXGI_FORMAT_R10G10B10A2_UNORM
+        );
+        assert_eq!(
+            negotiate_frame_format(DXGI_FORMAT_R16G16B16A16_FLOAT, &supported, true),
+            DXGI_FORMAT_B8G8R8A8_UNORM
+        );
+        assert_eq!(
+            negotiate_frame_format(DXGI_FORMAT_R10G10B10A2_UNORM, &supported, false),
+            DXGI_FORMAT_B8G8R8A8_UNORM
+        );
+        assert_eq!(
+            negotiate_frame_format(DXGI_FORMAT_R8G8B8A8_UNORM, &[], true),
+            DXGI_FORMAT_B8G8R8A8_UNORM
+        );
+    }
+
+    #[test]
+    fn dirty_rect_count() {
+        assert_eq!(size_of::<RECT>(), 16);
+        assert_eq!(item_count::<RECT>(0), 0);
+        assert_eq!(item_count::<RECT>(48), 3);
+        assert_eq!(item_count::<RECT>(50), 3);
+    }
+
+    #[test]
+    fn frame_metadata_query() {
+        let rects: Vec<RECT> = (0..5)
+            .map(|i| RECT {
+                left: i,
+                top: i,
+                right: i + 10,
+                bottom: i + 10,
+            })
+            .collect();
+        let required_size = (rects.len() * size_of::<RECT>()) as u32;
+
+        let mut calls = 0;
+        let queried = query_frame_metadata(|buffer_size, buffer: *mut RECT, required| {
+            calls += 1;
+            *required = required_size;
+            if buffer_size < required_size {
+                return Err(DXGI_ERROR_MORE_DATA.into());
+            }
+            // SAFETY: `buffer` has room for `required_size` bytes
+            unsafe { buffer.copy_from_nonoverlapping(rects.as_ptr(), rects.len()) };
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(calls, 2);
+        assert_eq!(queried, rects);
+
+        let empty = query_frame_metadata(|_, _: *mut RECT, required| {
+            *required = 0;
+            Ok(())
+        })
+        .unwrap();
+        assert!(empty.is_empty());
+
+        let failed = query_frame_metadata(|_, _: *mut RECT, _| Err(E_ACCESSDENIED.into()));
+        assert_eq!(failed.unwrap_err().code(), E_ACCESSDENIED);
+    }
+
+    #[test]
+    fn copy_box_clamping() {
+        let rect = |left, top, right, bottom| RECT {
+            left,
+            top,
+            right,
+            bottom,
+        };
+
+        let copy = copy_box(&rect(10, 20, 110, 220), 1920, 1080).unwrap();
+        assert_eq!(
+            (copy.left, copy.top, copy.right, copy.bottom),
+            (10, 20, 110, 220)
+        );
+        assert_eq!((copy.front, copy.back), (0, 1));
+
+        let copy = copy_box(&rect(-10, 1000, 2000, 1200), 1920, 1080).unwrap();
+        assert_eq!(
+            (copy.left, copy.top, copy.right, copy.bottom),
+            (0, 1000, 1920, 1080)
+        );
+
+        assert!(copy_box(&rect(10, 10, 10, 20), 1920, 1080).is_none());
+        assert!(copy_box(&rect(2000, 0, 2100, 100), 1920, 1080).is_none());
+    }
+
     #[test]
     fn refresh_rate_test() {
         use std::time::Duration;
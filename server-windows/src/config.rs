@@ -0,0 +1,313 @@
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+/// Command line options of the server.
+#[derive(Debug, PartialEq, Eq, Clone, Parser)]
+#[command(version, about = "WebRTC desktop streaming server")]
+pub struct Config {
+    /// Address to listen on. Use 0.0.0.0 to allow other machines to connect.
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    pub bind: IpAddr,
+
+    /// Port of the HTTP and signaling server.
+    #[arg(long, default_value_t = 9090)]
+    pub port: u16,
+
+    /// Minimum video bitrate in kbps.
+    #[arg(long, default_value_t = 64)]
+    pub min_bitrate: u32,
+
+    /// Maximum video bitrate in kbps.
+    #[arg(long, default_value_t = 100_000)]
+    pub max_bitrate: u32,
+
+    /// Video bitrate in kbps until the first bandwidth estimate. Useful on a fast LAN to skip the
+    /// ramp up.
+    #[arg(long)]
+    pub start_bitrate: Option<u32>,
+
+    /// Bitrate in kbps of the bandwidth estimate left for an audio track.
+    #[arg(long, default_value_t = 0)]
+    pub audio_bitrate: u32,
+
+    /// Close a session after this many seconds without input from the client.
+    #[arg(long)]
+    pub idle_timeout: Option<u64>,
+
+    /// Close a session after this many seconds regardless of activity.
+    #[arg(long)]
+    pub max_session_duration: Option<u64>,
+
+    /// Maximum number of pointer moves and wheel events per second accepted from the client.
+    #[arg(long, default_value_t = 500, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_input_rate: u32,
+
+    /// Maximum number of frames encoded per second.
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u32).range(1..))]
+    pub target_fps: u32,
+
+    /// Send the last frame again after this many milliseconds without screen changes. 0 disables
+    /// the repetition.
+    #[arg(long, default_value_t = 250)]
+    pub idle_repeat_interval: u64,
+
+    /// Size of the VBV buffer in kbits. Smaller buffers cap the size of the keyframes. Holds one
+    /// frame at the current bitrate by default.
+    #[arg(long)]
+    pub vbv_buffer_size: Option<u32>,
+
+    /// Number of times in a row the capture is reset after repeated errors before the session is
+    /// closed.
+    #[arg(long, default_value_t = 3)]
+    pub max_resets: u32,
+
+    /// Ignore the bitrate cap sent by the client in REMB packets.
+    #[arg(long)]
+    pub no_remb: bool,
+
+    /// Stream losslessly with H.264 High 4:4:4 if the GPU supports it. Only practical on a fast
+    /// LAN.
+    #[arg(long)]
+    pub lossless: bool,
+}
+
+/// Limits after which a session is closed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SessionLimits {
+    pub idle_timeout: Option<Duration>,
+    pub max_duration: Option<Duration>,
+}
+
+/// Encoder options of a session.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct EncoderOptions {
+    pub target_fps: u32,
+    pub idle_repeat_interval: Option<Duration>,
+    /// In bits.
+    pub vbv_buffer_size: Option<u32>,
+    pub max_resets: u32,
+    pub use_remb: bool,
+    pub lossless: bool,
+}
+
+/// Bitrate limits of the video stream in bits per second.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BitrateRange {
+    pub min: u32,
+    pub start: Option<u32>,
+    pub max: u32,
+    /// Reserved for audio out of the bandwidth estimate.
+    pub audio: u32,
+}
+
+impl Config {
+    /// Address that the server listens on.
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    /// Parses the command line, exiting with a usage error if the options are inconsistent.
+    pub fn parse_and_validate() -> Config {
+        let config = Config::parse();
+        if let Err(e) = config.validate() {
+            Config::command()
+                .error(ErrorKind::ArgumentConflict, e)
+                .exit();
+        }
+        config
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let start = self.start_bitrate.unwrap_or(self.min_bitrate);
+        if self.min_bitrate <= start && start <= self.max_bitrate {
+            Ok(())
+        } else {
+            Err(format!(
+                "Bitrates must satisfy min <= start <= max but got {} <= {} <= {}",
+                self.min_bitrate, start, self.max_bitrate
+            ))
+        }
+    }
+
+    pub fn session_limits(&self) -> SessionLimits {
+        SessionLimits {
+            idle_timeout: self.idle_timeout.map(Duration::from_secs),
+            max_duration: self.max_session_duration.map(Duration::from_secs),
+        }
+    }
+
+    /// Bitrate limits converted to bits per second.
+    pub fn bitrate_range(&self) -> BitrateRange {
+        let to_bps = |kbps: u32| kbps.saturating_mul(1000);
+        BitrateRange {
+            min: to_bps(self.min_bitrate),
+            start: self.start_bitrate.map(to_bps),
+            max: to_bps(self.max_bitrate),
+            audio: to_bps(self.audio_bitrate),
+        }
+    }
+
+    /// Encoder options with the sizes converted to bits.
+    pub fn encoder_options(&self) -> EncoderOptions {
+        EncoderOptions {
+            target_fps: self.target_fps,
+            idle_repeat_interval: Some(self.idle_repeat_interval)
+                .filter(|&millis| millis != 0)
+                .map(Duration::from_millis),
+            vbv_buffer_size: self.vbv_buffer_size.map(|kbits| kbits.saturating_mul(1000)),
+            max_resets: self.max_resets,
+            use_remb: !self.no_remb,
+            lossless: self.lossless,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_args() {
+        let config = Config::try_parse_from(["server-windows"]).unwrap();
+        assert_eq!(config.socket_addr(), ([127, 0, 0, 1], 9090).into());
+    }
+
+    #[test]
+    fn bind_and_port_args() {
+        let config =
+            Config::try_parse_from(["server-windows", "--bind", "0.0.0.0", "--port", "8080"])
+                .unwrap();
+        assert_eq!(config.socket_addr(), ([0, 0, 0, 0], 8080).into());
+
+        let config = Config::try_parse_from(["server-windows", "--bind", "::1"]).unwrap();
+        assert_eq!(config.socket_addr(), "[::1]:9090".parse().unwrap());
+    }
+
+    #[test]
+    fn config_clone() {
+        let config = Config::try_parse_from(["server-windows"]).unwrap();
+        let mut other = config.clone();
+        assert_eq!(config, other);
+
+        other.port = 8080;
+        assert_ne!(config, other);
+    }
+
+    #[test]
+    fn bitrate_args() {
+        let config = Config::try_parse_from(["server-windows"]).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.bitrate_range(),
+            BitrateRange {
+                min: 64_000,
+                start: None,
+                max: 100_000_000,
+                audio: 0,
+            }
+        );
+
+        let config = Config::try_parse_from([
+            "server-windows",
+            "--min-bitrate",
+            "1000",
+            "--start-bitrate",
+            "20000",
+            "--max-bitrate",
+            "50000",
+        ])
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.bitrate_range().start, Some(20_000_000));
+
+        let config = Config::try_parse_from(["server-windows", "--audio-bitrate", "128"]).unwrap();
+        assert_eq!(config.bitrate_range().audio, 128_000);
+
+        let config =
+            Config::try_parse_from(["server-windows", "--start-bitrate", "200000"]).unwrap();
+        assert!(config.validate().is_err());
+        let config = Config::try_parse_from(["server-windows", "--min-bitrate", "200000"]).unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn session_limit_args() {
+        let config = Config::try_parse_from(["server-windows"]).unwrap();
+        assert_eq!(
+            config.session_limits(),
+            SessionLimits {
+                idle_timeout: None,
+                max_duration: None,
+            }
+        );
+
+        let config = Config::try_parse_from([
+            "server-windows",
+            "--idle-timeout",
+            "300",
+            "--max-session-duration",
+            "3600",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.session_limits(),
+            SessionLimits {
+                idle_timeout: Some(Duration::from_secs(300)),
+                max_duration: Some(Duration::from_secs(3600)),
+            }
+        );
+    }
+
+    #[test]
+    fn encoder_option_args() {
+        let config = Config::try_parse_from(["server-windows"]).unwrap();
+        assert_eq!(
+            config.encoder_options(),
+            EncoderOptions {
+                target_fps: 60,
+                idle_repeat_interval: Some(Duration::from_millis(250)),
+                vbv_buffer_size: None,
+                max_resets: 3,
+                use_remb: true,
+                lossless: false,
+            }
+        );
+
+        let config = Config::try_parse_from([
+            "server-windows",
+            "--target-fps",
+            "30",
+            "--idle-repeat-interval",
+            "0",
+            "--vbv-buffer-size",
+            "100",
+            "--max-resets",
+            "0",
+            "--no-remb",
+            "--lossless",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.encoder_options(),
+            EncoderOptions {
+                target_fps: 30,
+                idle_repeat_interval: None,
+                vbv_buffer_size: Some(100_000),
+                max_resets: 0,
+                use_remb: false,
+                lossless: true,
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_args() {
+        assert!(Config::try_parse_from(["server-windows", "--bind", "localhost"]).is_err());
+        assert!(Config::try_parse_from(["server-windows", "--port", "65536"]).is_err());
+        assert!(Config::try_parse_from(["server-windows", "--max-input-rate", "0"]).is_err());
+        assert!(Config::try_parse_from(["server-windows", "--target-fps", "0"]).is_err());
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::Instant;
+
+/// Token bucket that limits the number of injected events per second.
+pub struct RateLimiter {
+    /// Tokens added per second. Also the size of the bucket.
+    rate: f64,
+    tokens: f64,
+    last_update: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// Creates a new `RateLimiter` allowing bursts of up to `rate` events, refilled at `rate`
+    /// events per second.
+    pub fn new(rate: u32) -> RateLimiter {
+        RateLimiter {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_update: None,
+        }
+    }
+
+    /// Returns true if an event arriving at `now` is allowed through.
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        if let Some(last_update) = self.last_update {
+            let elapsed = now.saturating_duration_since(last_update).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        }
+        self.last_update = Some(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn rate_limiter_caps_throughput() {
+        let mut limiter = RateLimiter::new(100);
+        let start = Instant::now();
+
+        // The burst is limited to the size of the bucket
+        let allowed = (0..1000).filter(|_| limiter.try_acquire(start)).count();
+        assert_eq!(allowed, 100);
+
+        // Events every 1 ms for a second only get 100 through
+        let allowed = (1..=1000)
+            .filter(|&i| limiter.try_acquire(start + Duration::from_millis(i)))
+            .count();
+        assert!((99..=101).contains(&allowed), "{allowed}");
+
+        // Refilled after being idle
+        let later = start + Duration::from_secs(10);
+        let allowed = (0..1000).filter(|_| limiter.try_acquire(later)).count();
+        assert_eq!(allowed, 100);
+    }
+}
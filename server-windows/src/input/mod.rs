@@ -1,7 +1,15 @@
+mod limiter;
 mod pointer;
+mod wheel;
 
-use self::pointer::{PointerDevice, PointerEvent};
-use std::{future::Future, pin::Pin, sync::Arc};
+use self::{
+    limiter::RateLimiter,
+    pointer::{PointerDevice, PointerEvent, ScreenRect},
+    wheel::WheelEvent,
+};
+use crate::{nvidia::EncoderHandle, session::ActivitySender};
+use serde::Deserialize;
+use std::{future::Future, pin::Pin, sync::Arc, time::Instant};
 use webrtc::{data::data_channel::DataChannel, data_channel::RTCDataChannel};
 use windows::{
     core::HRESULT,
@@ -10,8 +18,57 @@ use windows::{
 
 const MESSAGE_SIZE: usize = 1500;
 
+/// Messages received on the data channel.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ControlEvent {
+    Pointer(PointerEvent),
+    Wheel(WheelEvent),
+    Stream(StreamEvent),
+}
+
+/// Controls of the video stream.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum StreamEvent {
+    /// Stop capturing while the client is not showing the video.
+    Pause,
+    Resume,
+    /// Request a keyframe.
+    Keyframe,
+    /// Use a fixed bitrate in kbps, or follow the bandwidth estimate again if missing.
+    Bitrate {
+        kbps: Option<u32>,
+    },
+}
+
+impl StreamEvent {
+    fn apply(self, encoder: &EncoderHandle) {
+        match self {
+            StreamEvent::Pause => encoder.pause(),
+            StreamEvent::Resume => encoder.resume(),
+            StreamEvent::Keyframe => encoder.force_idr(),
+            StreamEvent::Bitrate { kbps } => {
+                encoder.set_bitrate(kbps.map(|kbps| kbps.saturating_mul(1000)))
+            }
+        }
+    }
+}
+
+/// Settings of the input injection for a session.
+#[derive(Debug, Clone, Copy)]
+pub struct InputSettings {
+    /// Display that receives the input.
+    pub display_index: u32,
+    /// Maximum number of pointer moves and wheel events injected per second.
+    pub max_event_rate: u32,
+}
+
 pub fn controls_handler(
     data_channel: Arc<RTCDataChannel>,
+    settings: InputSettings,
+    activity: ActivitySender,
+    encoder: EncoderHandle,
 ) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>> {
     Box::pin(async move {
         let data_channel = Arc::clone(&data_channel);
@@ -28,28 +85,56 @@ pub fn controls_handler(
 
                 let raw = Arc::clone(&raw);
                 tokio::spawn(async move {
-                    let _ = control_loop(raw).await;
+                    let _ = control_loop(raw, settings, activity, encoder).await;
                 });
             })
         }));
     })
 }
 
-async fn control_loop(data_channel: Arc<DataChannel>) {
+async fn control_loop(
+    data_channel: Arc<DataChannel>,
+    settings: InputSettings,
+    activity: ActivitySender,
+    encoder: EncoderHandle,
+) {
     let device = PointerDevice::new().expect("Failed to create `PointerDevice`");
+    let screen = match ScreenRect::of_display(settings.display_index) {
+        Ok(screen) => screen,
+        Err(e) => {
+            log::error!(
+                "Unable to get the position of display {}: {e}",
+                settings.display_index
+            );
+            ScreenRect::primary()
+        }
+    };
+    let virtual_screen = wheel::virtual_screen();
+    let mut limiter = RateLimiter::new(settings.max_event_rate);
     let mut buffer = vec![0u8; MESSAGE_SIZE];
 
     let not_ready = HRESULT(ERROR_NOT_READY.0 as _);
 
     while let Ok((n, is_string)) = data_channel.read_data_channel(&mut buffer).await {
+        activity.notify();
         if !is_string {
             continue;
         }
 
         if let Ok(s) = std::str::from_utf8(&buffer[..n]) {
-            match serde_json::from_str::<PointerEvent>(s) {
-                Ok(p) => {
-                    let p: POINTER_TYPE_INFO = p.into();
+            match serde_json::from_str::<ControlEvent>(s) {
+                Ok(ControlEvent::Pointer(p)) => {
+                    if !p.is_valid(&screen) {
+                        log::warn!("Rejected out of range pointer event: {p:?}");
+                        continue;
+                    }
+                    // Only moves are dropped so that no contact is left pressed
+                    if p.is_coalescable() && !limiter.try_acquire(Instant::now()) {
+                        log::debug!("Dropped pointer event over the rate limit");
+                        continue;
+                    }
+
+                    let p: POINTER_TYPE_INFO = p.to_screen(&screen).into();
 
                     loop {
                         match device.inject_pointer_input(std::array::from_ref(&p)) {
@@ -64,8 +149,68 @@ async fn control_loop(data_channel: Arc<DataChannel>) {
                         }
                     }
                 }
+                Ok(ControlEvent::Wheel(w)) => {
+                    if !w.is_valid() {
+                        log::warn!("Rejected out of range wheel event: {w:?}");
+                        continue;
+                    }
+                    if !limiter.try_acquire(Instant::now()) {
+                        log::debug!("Dropped wheel event over the rate limit");
+                        continue;
+                    }
+
+                    let inputs = w.to_inputs(&screen, &virtual_screen);
+                    if let Err(e) = wheel::send_input(&inputs) {
+                        log::error!("send_input error: {e}");
+                    }
+                }
+                Ok(ControlEvent::Stream(event)) => {
+                    log::info!("Stream control: {event:?}");
+                    event.apply(&encoder);
+                }
                 Err(e) => log::error!("serde_json::from_str error: {e}"),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream_event(json: &str) -> Option<StreamEvent> {
+        match serde_json::from_str::<ControlEvent>(json).unwrap() {
+            ControlEvent::Stream(event) => Some(event),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn stream_event_parsing() {
+        assert_eq!(
+            stream_event(r#"{"type":"pause"}"#),
+            Some(StreamEvent::Pause)
+        );
+        assert_eq!(
+            stream_event(r#"{"type":"resume"}"#),
+            Some(StreamEvent::Resume)
+        );
+        assert_eq!(
+            stream_event(r#"{"type":"keyframe"}"#),
+            Some(StreamEvent::Keyframe)
+        );
+        assert_eq!(
+            stream_event(r#"{"type":"bitrate","kbps":5000}"#),
+            Some(StreamEvent::Bitrate { kbps: Some(5000) })
+        );
+        assert_eq!(
+            stream_event(r#"{"type":"bitrate"}"#),
+            Some(StreamEvent::Bitrate { kbps: None })
+        );
+
+        // Wheel events also have a type
+        let json = r#"{"type":"wheel","x":0.5,"y":0.5,"deltaX":0,"deltaY":100,"deltaMode":0}"#;
+        assert_eq!(stream_event(json), None);
+        assert!(serde_json::from_str::<ControlEvent>(r#"{"type":"stop"}"#).is_err());
+    }
+}
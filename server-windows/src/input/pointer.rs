@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use windows::Win32::{
     Foundation::{HANDLE, HWND, POINT, RECT},
+    Graphics::Dxgi::{CreateDXGIFactory1, IDXGIFactory1},
     UI::{
         Controls::{
             CreateSyntheticPointerDevice, DestroySyntheticPointerDevice, HSYNTHETICPOINTERDEVICE,
@@ -13,8 +14,9 @@ use windows::Win32::{
             POINTER_PEN_INFO, POINTER_TOUCH_INFO,
         },
         WindowsAndMessaging::{
-            PEN_MASK_PRESSURE, PEN_MASK_ROTATION, PEN_MASK_TILT_X, PEN_MASK_TILT_Y,
-            POINTER_MOD_CTRL, POINTER_MOD_SHIFT, PT_MOUSE, PT_PEN, PT_TOUCH,
+            GetSystemMetrics, PEN_FLAG_BARREL, PEN_FLAG_ERASER, PEN_MASK_PRESSURE,
+            PEN_MASK_ROTATION, PEN_MASK_TILT_X, PEN_MASK_TILT_Y, POINTER_MOD_CTRL,
+            POINTER_MOD_SHIFT, PT_MOUSE, PT_PEN, PT_TOUCH, SM_CXSCREEN, SM_CYSCREEN,
             TOUCH_MASK_CONTACTAREA, TOUCH_MASK_PRESSURE,
         },
     },
@@ -22,6 +24,11 @@ use windows::Win32::{
 
 const MAX_CONTACTS: usize = 10;
 
+/// Bit of `PointerEvent::buttons` for the barrel button of a pen.
+const BARREL_BUTTON: u32 = 2;
+/// Bit of `PointerEvent::buttons` for the eraser end of a pen.
+const ERASER_BUTTON: u32 = 32;
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
 pub enum PointerType {
     #[serde(rename = "mouse")]
@@ -88,11 +95,19 @@ pub struct PointerEvent {
     y: f64,
     width: f64,
     height: f64,
+    /// The position and size are fractions of the stream's dimensions instead of pixels.
+    #[serde(default)]
+    normalized: bool,
 
+    #[serde(rename = "pointerType")]
     pointer_type: Option<PointerType>,
 
     pressure: Option<f64>,
 
+    /// Pressed buttons as in `MouseEvent.buttons`.
+    #[serde(default)]
+    buttons: u32,
+
     #[serde(rename = "penExtra")]
     pen_extra: Option<PenExtra>,
 
@@ -100,6 +115,109 @@ pub struct PointerEvent {
     modifier_keys: Option<ModifierKeys>,
 }
 
+/// Area of the desktop shown to the client, in pixels.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct ScreenRect {
+    pub left: i32,
+    pub top: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ScreenRect {
+    /// Returns the area of the primary monitor.
+    pub fn primary() -> ScreenRect {
+        // SAFETY: Windows API calls
+        let (width, height) =
+            unsafe { (GetSystemMetrics(SM_CXSCREEN), GetSystemMetrics(SM_CYSCREEN)) };
+        ScreenRect {
+            left: 0,
+            top: 0,
+            width: width.max(0) as u32,
+            height: height.max(0) as u32,
+        }
+    }
+
+    /// Returns the area of the display at `display_index` on the virtual desktop. This is the
+    /// same display that `ScreenDuplicator` captures with that index.
+    pub fn of_display(display_index: u32) -> Result<ScreenRect, windows::core::Error> {
+        // SAFETY: Windows API calls
+        let desc = unsafe {
+            let factory: IDXGIFactory1 = CreateDXGIFactory1()?;
+            // The D3D11 device used for the capture is created on the default adapter
+            let adapter = factory.EnumAdapters1(0)?;
+            adapter.EnumOutputs(display_index)?.GetDesc()?
+        };
+        Ok(ScreenRect::from(desc.DesktopCoordinates))
+    }
+}
+
+impl From<RECT> for ScreenRect {
+    fn from(rect: RECT) -> Self {
+        ScreenRect {
+            left: rect.left,
+            top: rect.top,
+            width: (rect.right - rect.left).max(0) as u32,
+            height: (rect.bottom - rect.top).max(0) as u32,
+        }
+    }
+}
+
+impl PointerEvent {
+    /// Returns false if the event has coordinates outside of the stream or nonsensical values.
+    pub fn is_valid(&self, screen: &ScreenRect) -> bool {
+        let (max_x, max_y) = if self.normalized {
+            (1.0, 1.0)
+        } else {
+            (screen.width as f64, screen.height as f64)
+        };
+        let in_range = |value: f64, max: f64| (0.0..=max).contains(&value);
+
+        in_range(self.x, max_x)
+            && in_range(self.y, max_y)
+            && in_range(self.width, max_x)
+            && in_range(self.height, max_y)
+            && self
+                .pressure
+                .map_or(true, |pressure| in_range(pressure, 1.0))
+            && self.pen_extra.map_or(true, |pen_extra| {
+                (-90..=90).contains(&pen_extra.tilt_x)
+                    && (-90..=90).contains(&pen_extra.tilt_y)
+                    && pen_extra.twist < 360
+            })
+    }
+
+    /// Returns true for events that can be dropped when there are too many of them, since the
+    /// next one supersedes it.
+    pub fn is_coalescable(&self) -> bool {
+        matches!(
+            self.event_type,
+            PointerEventType::Move | PointerEventType::RawUpdate
+        )
+    }
+
+    /// Converts the coordinates relative to the stream to coordinates on the virtual desktop,
+    /// where `screen` is the captured display.
+    pub fn to_screen(mut self, screen: &ScreenRect) -> PointerEvent {
+        if self.normalized {
+            let to_pixels = |fraction: f64, length: u32| {
+                // A fraction of 1.0 would be just outside the screen
+                let max = length.saturating_sub(1) as f64;
+                (fraction.clamp(0.0, 1.0) * length as f64).min(max)
+            };
+            self.x = screen.left as f64 + to_pixels(self.x, screen.width);
+            self.y = screen.top as f64 + to_pixels(self.y, screen.height);
+            self.width *= screen.width as f64;
+            self.height *= screen.height as f64;
+            self.normalized = false;
+        } else {
+            self.x += screen.left as f64;
+            self.y += screen.top as f64;
+        }
+        self
+    }
+}
+
 impl Into<POINTER_TYPE_INFO> for PointerEvent {
     fn into(self) -> POINTER_TYPE_INFO {
         let mut pointer_flags = match self.event_type {
@@ -207,19 +325,29 @@ impl Into<POINTER_TYPE_INFO> for PointerEvent {
             }
         } else {
             let mut pen_mask = 0;
+            if self.pressure.is_some() {
+                pen_mask |= PEN_MASK_PRESSURE;
+            }
 
             let (twist, tilt_x, tilt_y) = if let Some(pen_extra) = self.pen_extra {
-                pen_mask |=
-                    PEN_MASK_PRESSURE | PEN_MASK_ROTATION | PEN_MASK_TILT_X | PEN_MASK_TILT_Y;
+                pen_mask |= PEN_MASK_ROTATION | PEN_MASK_TILT_X | PEN_MASK_TILT_Y;
                 (pen_extra.twist, pen_extra.tilt_x, pen_extra.tilt_y)
             } else {
                 (0, 0, 0)
             };
 
+            let mut pen_flags = 0;
+            if self.buttons & BARREL_BUTTON != 0 {
+                pen_flags |= PEN_FLAG_BARREL;
+            }
+            if self.buttons & ERASER_BUTTON != 0 {
+                pen_flags |= PEN_FLAG_ERASER;
+            }
+
             POINTER_TYPE_INFO_0 {
                 penInfo: POINTER_PEN_INFO {
                     pointerInfo: pointer_info,
-                    penFlags: 0, // TODO: Look at MouseEvent to see if this is in there
+                    penFlags: pen_flags,
                     penMask: pen_mask,
                     pressure,
                     rotation: twist,
@@ -285,3 +413,116 @@ impl PointerDevice {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pointer_event(x: f64, y: f64, normalized: bool) -> PointerEvent {
+        let json = r#"{"type":"pointermove","pointerId":1,"isPrimary":true,"x":0,"y":0,"width":0.25,"height":0.5}"#;
+        PointerEvent {
+            x,
+            y,
+            normalized,
+            ..serde_json::from_str(json).unwrap()
+        }
+    }
+
+    #[test]
+    fn normalized_coordinates() {
+        let screen = ScreenRect {
+            left: 0,
+            top: 0,
+            width: 1920,
+            height: 1080,
+        };
+
+        let p = pointer_event(0.5, 0.25, true).to_screen(&screen);
+        assert_eq!((p.x, p.y), (960.0, 270.0));
+        assert_eq!((p.width, p.height), (480.0, 540.0));
+        assert!(!p.normalized);
+
+        let p = pointer_event(1.0, 1.5, true).to_screen(&screen);
+        assert_eq!((p.x, p.y), (1919.0, 1079.0));
+
+        // Pixel coordinates are not scaled
+        let p = pointer_event(100.0, 200.0, false).to_screen(&screen);
+        assert_eq!((p.x, p.y), (100.0, 200.0));
+    }
+
+    #[test]
+    fn out_of_range_events() {
+        let screen = ScreenRect {
+            left: 1920,
+            top: 0,
+            width: 1920,
+            height: 1080,
+        };
+
+        assert!(pointer_event(0.5, 0.5, true).is_valid(&screen));
+        assert!(pointer_event(1920.0, 1080.0, false).is_valid(&screen));
+        assert!(!pointer_event(1.5, 0.5, true).is_valid(&screen));
+        assert!(!pointer_event(0.5, -0.1, true).is_valid(&screen));
+        assert!(!pointer_event(f64::NAN, 0.5, true).is_valid(&screen));
+        assert!(!pointer_event(2000.0, 100.0, false).is_valid(&screen));
+        assert!(!pointer_event(100.0, f64::INFINITY, false).is_valid(&screen));
+    }
+
+    #[test]
+    fn pen_info() {
+        let screen = ScreenRect {
+            left: 0,
+            top: 0,
+            width: 1920,
+            height: 1080,
+        };
+        let json = r#"{"type":"pointerdown","pointerId":2,"isPrimary":true,"x":100,"y":200,"width":1,"height":1,"pointerType":"pen","pressure":0.5,"buttons":3,"penExtra":{"tiltX":-30,"tiltY":45,"twist":90}}"#;
+        let p: PointerEvent = serde_json::from_str(json).unwrap();
+        assert!(p.is_valid(&screen));
+
+        let info: POINTER_TYPE_INFO = p.to_screen(&screen).into();
+        assert_eq!(info.r#type, PT_PEN);
+        // SAFETY: `penInfo` is the active field for `PT_PEN`
+        let pen_info = unsafe { info.Anonymous.penInfo };
+        assert_eq!(pen_info.pressure, 512);
+        assert_eq!((pen_info.tiltX, pen_info.tiltY), (-30, 45));
+        assert_eq!(pen_info.rotation, 90);
+        assert_eq!(pen_info.penFlags, PEN_FLAG_BARREL);
+        assert_eq!(
+            pen_info.penMask,
+            PEN_MASK_PRESSURE | PEN_MASK_ROTATION | PEN_MASK_TILT_X | PEN_MASK_TILT_Y
+        );
+
+        // The tilt is at most 90 degrees
+        let json = json.replace("-30", "-120");
+        let p: PointerEvent = serde_json::from_str(&json).unwrap();
+        assert!(!p.is_valid(&screen));
+    }
+
+    #[test]
+    fn secondary_monitor_coordinates() {
+        // Right of a 1920x1080 primary monitor
+        let screen = ScreenRect::from(RECT {
+            left: 1920,
+            top: -180,
+            right: 4480,
+            bottom: 1260,
+        });
+        assert_eq!((screen.width, screen.height), (2560, 1440));
+
+        let p = pointer_event(0.5, 0.5, true).to_screen(&screen);
+        assert_eq!((p.x, p.y), (3200.0, 540.0));
+        let p = pointer_event(100.0, 200.0, false).to_screen(&screen);
+        assert_eq!((p.x, p.y), (2020.0, 20.0));
+
+        // Left of the primary monitor
+        let screen = ScreenRect::from(RECT {
+            left: -1280,
+            top: 0,
+            right: 0,
+            bottom: 1024,
+        });
+        let p = pointer_event(0.0, 1.0, true).to_screen(&screen);
+        assert_eq!((p.x, p.y), (-1280.0, 1023.0));
+    }
+}
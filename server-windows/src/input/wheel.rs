@@ -0,0 +1,225 @@
+use super::pointer::ScreenRect;
+use serde::{Deserialize, Serialize};
+use std::mem::size_of;
+use windows::Win32::UI::{
+    Input::KeyboardAndMouse::{
+        SendInput, INPUT, INPUT_0, INPUT_MOUSE, MOUSEEVENTF_ABSOLUTE, MOUSEEVENTF_HWHEEL,
+        MOUSEEVENTF_MOVE, MOUSEEVENTF_VIRTUALDESK, MOUSEEVENTF_WHEEL, MOUSEINPUT,
+        MOUSE_EVENT_FLAGS,
+    },
+    WindowsAndMessaging::{
+        GetSystemMetrics, SM_CXVIRTUALSCREEN, SM_CYVIRTUALSCREEN, SM_XVIRTUALSCREEN,
+        SM_YVIRTUALSCREEN, WHEEL_DELTA,
+    },
+};
+
+/// Pixels scrolled by one notch of the wheel in most browsers.
+const PIXELS_PER_NOTCH: f64 = 100.0;
+/// Lines scrolled by one notch of the wheel in most browsers.
+const LINES_PER_NOTCH: f64 = 3.0;
+/// Larger deltas are most likely not from a real wheel.
+const MAX_NOTCHES: f64 = 100.0;
+
+/// `deltaMode` of a DOM `WheelEvent`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+#[serde(from = "u32", into = "u32")]
+pub enum DeltaMode {
+    Pixel,
+    Line,
+    Page,
+}
+
+impl From<u32> for DeltaMode {
+    fn from(value: u32) -> Self {
+        match value {
+            1 => DeltaMode::Line,
+            2 => DeltaMode::Page,
+            _ => DeltaMode::Pixel,
+        }
+    }
+}
+
+impl From<DeltaMode> for u32 {
+    fn from(value: DeltaMode) -> Self {
+        match value {
+            DeltaMode::Pixel => 0,
+            DeltaMode::Line => 1,
+            DeltaMode::Page => 2,
+        }
+    }
+}
+
+/// Scrolling from the client. The position is a fraction of the stream's dimensions.
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct WheelEvent {
+    x: f64,
+    y: f64,
+    #[serde(rename = "deltaX")]
+    delta_x: f64,
+    #[serde(rename = "deltaY")]
+    delta_y: f64,
+    #[serde(rename = "deltaMode", default = "default_delta_mode")]
+    delta_mode: DeltaMode,
+}
+
+fn default_delta_mode() -> DeltaMode {
+    DeltaMode::Pixel
+}
+
+impl WheelEvent {
+    /// Returns false if the event is outside of the stream or scrolls an absurd amount.
+    pub fn is_valid(&self) -> bool {
+        let in_range = |value: f64, min: f64, max: f64| (min..=max).contains(&value);
+        in_range(self.x, 0.0, 1.0)
+            && in_range(self.y, 0.0, 1.0)
+            && in_range(self.notches(self.delta_x), -MAX_NOTCHES, MAX_NOTCHES)
+            && in_range(self.notches(self.delta_y), -MAX_NOTCHES, MAX_NOTCHES)
+    }
+
+    /// Converts a delta to notches of the wheel.
+    fn notches(&self, delta: f64) -> f64 {
+        match self.delta_mode {
+            DeltaMode::Pixel => delta / PIXELS_PER_NOTCH,
+            DeltaMode::Line => delta / LINES_PER_NOTCH,
+            DeltaMode::Page => delta,
+        }
+    }
+
+    /// Returns the inputs that move the cursor to the event's position on `screen` then scroll.
+    /// `virtual_screen` is the bounding box of all the displays.
+    pub fn to_inputs(&self, screen: &ScreenRect, virtual_screen: &ScreenRect) -> Vec<INPUT> {
+        let mut inputs = Vec::with_capacity(3);
+
+        // Absolute coordinates are normalized to 0..=65535 over the virtual desktop
+        let x = (screen.left - virtual_screen.left) as f64 + self.x * screen.width as f64;
+        let y = (screen.top - virtual_screen.top) as f64 + self.y * screen.height as f64;
+        let dx = (x * 65535.0 / virtual_screen.width.max(1) as f64) as i32;
+        let dy = (y * 65535.0 / virtual_screen.height.max(1) as f64) as i32;
+        inputs.push(mouse_input(
+            dx,
+            dy,
+            0,
+            MOUSEEVENTF_MOVE | MOUSEEVENTF_ABSOLUTE | MOUSEEVENTF_VIRTUALDESK,
+        ));
+
+        let to_wheel_data = |delta: f64| (self.notches(delta) * WHEEL_DELTA as f64) as i32;
+        // The DOM scrolls down with a positive delta while Windows scrolls up
+        let wheel = -to_wheel_data(self.delta_y);
+        if wheel != 0 {
+            inputs.push(mouse_input(0, 0, wheel, MOUSEEVENTF_WHEEL));
+        }
+        let hwheel = to_wheel_data(self.delta_x);
+        if hwheel != 0 {
+            inputs.push(mouse_input(0, 0, hwheel, MOUSEEVENTF_HWHEEL));
+        }
+        inputs
+    }
+}
+
+fn mouse_input(dx: i32, dy: i32, mouse_data: i32, flags: MOUSE_EVENT_FLAGS) -> INPUT {
+    INPUT {
+        r#type: INPUT_MOUSE,
+        Anonymous: INPUT_0 {
+            mi: MOUSEINPUT {
+                dx,
+                dy,
+                mouseData: mouse_data as _,
+                dwFlags: flags,
+                time: 0,
+                dwExtraInfo: 0,
+            },
+        },
+    }
+}
+
+/// Returns the bounding box of all the displays.
+pub fn virtual_screen() -> ScreenRect {
+    // SAFETY: Windows API calls
+    unsafe {
+        ScreenRect {
+            left: GetSystemMetrics(SM_XVIRTUALSCREEN),
+            top: GetSystemMetrics(SM_YVIRTUALSCREEN),
+            width: GetSystemMetrics(SM_CXVIRTUALSCREEN).max(0) as u32,
+            height: GetSystemMetrics(SM_CYVIRTUALSCREEN).max(0) as u32,
+        }
+    }
+}
+
+/// Injects the inputs as if they came from the mouse.
+pub fn send_input(inputs: &[INPUT]) -> Result<(), windows::core::Error> {
+    // SAFETY: Windows API call
+    let sent = unsafe { SendInput(inputs, size_of::<INPUT>() as i32) };
+    if sent as usize == inputs.len() {
+        Ok(())
+    } else {
+        Err(windows::core::Error::from_win32())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mouse(input: &INPUT) -> MOUSEINPUT {
+        assert_eq!(input.r#type, INPUT_MOUSE);
+        // SAFETY: All the inputs are mouse inputs
+        unsafe { input.Anonymous.mi }
+    }
+
+    #[test]
+    fn wheel_event_inputs() {
+        let json = r#"{"x":0.5,"y":0.5,"deltaX":0,"deltaY":100,"deltaMode":0}"#;
+        let event: WheelEvent = serde_json::from_str(json).unwrap();
+        assert!(event.is_valid());
+
+        let screen = ScreenRect {
+            left: 0,
+            top: 0,
+            width: 1920,
+            height: 1080,
+        };
+        let inputs = event.to_inputs(&screen, &screen);
+        assert_eq!(inputs.len(), 2);
+
+        let moved = mouse(&inputs[0]);
+        assert_eq!((moved.dx, moved.dy), (32767, 32767));
+        let wheel = mouse(&inputs[1]);
+        assert_eq!(wheel.mouseData as i32, -(WHEEL_DELTA as i32));
+        assert_eq!(wheel.dwFlags, MOUSEEVENTF_WHEEL);
+    }
+
+    #[test]
+    fn horizontal_wheel_event_inputs() {
+        let json = r#"{"x":0,"y":0,"deltaX":-3,"deltaY":0,"deltaMode":1}"#;
+        let event: WheelEvent = serde_json::from_str(json).unwrap();
+
+        let screen = ScreenRect {
+            left: 1920,
+            top: 0,
+            width: 1920,
+            height: 1080,
+        };
+        let virtual_screen = ScreenRect {
+            left: 0,
+            top: 0,
+            width: 3840,
+            height: 1080,
+        };
+        let inputs = event.to_inputs(&screen, &virtual_screen);
+        assert_eq!(inputs.len(), 2);
+
+        let moved = mouse(&inputs[0]);
+        assert_eq!((moved.dx, moved.dy), (32767, 0));
+        let hwheel = mouse(&inputs[1]);
+        assert_eq!(hwheel.mouseData as i32, -(WHEEL_DELTA as i32));
+        assert_eq!(hwheel.dwFlags, MOUSEEVENTF_HWHEEL);
+    }
+
+    #[test]
+    fn invalid_wheel_events() {
+        let json = r#"{"x":1.5,"y":0,"deltaX":0,"deltaY":100}"#;
+        assert!(!serde_json::from_str::<WheelEvent>(json).unwrap().is_valid());
+        let json = r#"{"x":0,"y":0,"deltaX":0,"deltaY":1000000}"#;
+        assert!(!serde_json::from_str::<WheelEvent>(json).unwrap().is_valid());
+    }
+}
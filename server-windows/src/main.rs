@@ -1,17 +1,39 @@
 mod capture;
+mod config;
 mod device;
 mod input;
 mod nvidia;
 mod server;
+mod session;
+mod shutdown;
 mod signaler;
 
-use std::net::SocketAddr;
+use config::Config;
+use shutdown::Shutdown;
+use std::time::Duration;
+
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 2)]
 async fn main() {
     env_logger::init();
-    let port: u16 = 9090;
-    let socket_addr: SocketAddr = ([0, 0, 0, 0], port).into();
+    let config = Config::parse_and_validate();
+    let socket_addr = config.socket_addr();
     println!("Serving from http://{socket_addr}");
-    server::http_server(socket_addr).await;
+
+    let shutdown = Shutdown::new();
+    server::http_server(
+        socket_addr,
+        config.bitrate_range(),
+        config.session_limits(),
+        config.encoder_options(),
+        config.max_input_rate,
+        shutdown.listener(),
+    )
+    .await;
+
+    log::info!("Shutting down");
+    if !shutdown.shutdown(SHUTDOWN_GRACE_PERIOD).await {
+        log::warn!("Streaming sessions did not exit within {SHUTDOWN_GRACE_PERIOD:?}");
+    }
 }
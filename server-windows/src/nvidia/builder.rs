@@ -1,6 +1,9 @@
-use super::encoder::start_encoder;
-use crate::{capture::ScreenDuplicator, device::create_d3d11_device};
-use std::{collections::HashMap, sync::Arc};
+use super::{
+    encoder::{start_encoder, EncoderSettings},
+    handle::{encoder_handle, EncoderControl, EncoderHandle},
+};
+use crate::{capture::ScreenDuplicator, device::create_d3d11_device, shutdown::ShutdownListener};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 use webrtc::{
     rtp_transceiver::{rtp_codec::RTCRtpCodecCapability, RTCRtpTransceiver},
     track::track_local::track_local_static_rtp::TrackLocalStaticRTP,
@@ -12,13 +15,29 @@ use webrtc_helper::{
     peer::IceConnectionState,
 };
 use windows::Win32::Graphics::{
-    Direct3D11::ID3D11Device,
+    Direct3D11::{ID3D11Device, D3D11_FORMAT_SUPPORT_TEXTURE2D},
     Dxgi::Common::{
         DXGI_FORMAT, DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_R10G10B10A2_UNORM,
         DXGI_FORMAT_R8G8B8A8_UNORM,
     },
 };
 
+/// Texture formats that NVENC accepts as input, from most to least preferred.
+pub const ENCODER_INPUT_FORMATS: [DXGI_FORMAT; 3] = [
+    DXGI_FORMAT_B8G8R8A8_UNORM,
+    DXGI_FORMAT_R10G10B10A2_UNORM,
+    DXGI_FORMAT_R8G8B8A8_UNORM,
+];
+
+/// Trade-off between bandwidth and image quality.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StreamQuality {
+    /// Low latency encoding with the bitrate following the bandwidth estimate.
+    Default,
+    /// Mathematically lossless encoding. Only practical on a fast LAN.
+    Lossless,
+}
+
 pub struct NvidiaEncoderBuilder {
     inner_builder: nvenc::EncoderBuilder<nvenc::DirectX11Device>,
     device: ID3D11Device,
@@ -27,6 +46,11 @@ pub struct NvidiaEncoderBuilder {
     display_index: u32,
     display_formats: Vec<DXGI_FORMAT>,
     supported_codecs: Vec<Codec>,
+    quality: StreamQuality,
+    settings: EncoderSettings,
+    handle: EncoderHandle,
+    control: EncoderControl,
+    shutdown: ShutdownListener,
 }
 
 impl EncoderBuilder for NvidiaEncoderBuilder {
@@ -68,7 +92,7 @@ impl EncoderBuilder for NvidiaEncoderBuilder {
                 }
             };
 
-        let (codec, profile) = {
+        let (codec, profile, level) = {
             match codec_capability.mime_type.as_str() {
                 "video/H264" => {
                     let profile =
@@ -79,7 +103,8 @@ impl EncoderBuilder for NvidiaEncoderBuilder {
                                 codec_capability.sdp_fmtp_line
                             ),
                         };
-                    (nvenc::Codec::H264, profile)
+                    let level = h264_level_from_sdp_fmtp_line(&codec_capability.sdp_fmtp_line);
+                    (nvenc::Codec::H264, profile, level)
                 }
                 "video/H265" => {
                     todo!("Implement HEVC parsing")
@@ -89,7 +114,10 @@ impl EncoderBuilder for NvidiaEncoderBuilder {
             }
         };
 
-        log::info!("NvidiaEncoderBuilder::build with codec {codec:?} and profile {profile:?}");
+        // Only logged since nvenc-rs has no level setting, NVENC picks one that fits the stream
+        log::info!(
+            "NvidiaEncoderBuilder::build with codec {codec:?}, profile {profile:?} and level {level:?}"
+        );
 
         if let Err(e) = self.inner_builder.with_codec(codec) {
             panic!("Encoder does not support the codec `{codec:?}`: {e}");
@@ -102,41 +130,52 @@ impl EncoderBuilder for NvidiaEncoderBuilder {
             }
         };
 
-        let new_settings = supported_encode_presets.contains(&nvenc::EncodePreset::P4);
-
-        let (preset, tuning_info, multi_pass, _rc_mode) = if new_settings {
-            // Equivalent settings for the old LowLatencyDefault and CBR_HQ:
-            // https://docs.nvidia.com/video-technologies/video-codec-sdk/nvenc-preset-migration-guide/
-            (
-                nvenc::EncodePreset::P4,
-                nvenc::TuningInfo::UltraLowLatency,
-                nvenc::MultiPassSetting::FullResolution,
-                Option::<()>::None, // TODO: Implement RC mode settings
-            )
-        } else {
-            todo!("Need to first implement RC mode settings in nvenc")
+        let quality = effective_quality(self.quality, profile);
+
+        let (quality, encode_settings) =
+            match select_encode_settings(quality, &supported_encode_presets) {
+                None if quality == StreamQuality::Lossless => {
+                    log::warn!(
+                        "Encoder has no lossless preset, falling back to the default quality"
+                    );
+                    let quality = StreamQuality::Default;
+                    let settings = select_encode_settings(quality, &supported_encode_presets);
+                    (quality, settings)
+                }
+                settings => (quality, settings),
+            };
+        let (preset, tuning_info) = match encode_settings {
+            Some(settings) => settings,
+            None => {
+                log::error!("Encoder does not support the P1-P7 presets");
+                return;
+            }
         };
 
-        let configure_encoder =
-            |builder: &mut nvenc::EncoderBuilder<nvenc::DirectX11Device>| -> nvenc::Result<()> {
-                builder
-                    .with_codec_profile(profile)?
-                    .with_encode_preset(preset)?
-                    .with_tuning_info(tuning_info)?
-                    .set_multi_pass(multi_pass)?;
-                // TODO: set_rc_mode(rc_mode)
-                Ok(())
-            };
+        // The lossless tuning makes NVENC use constant QP 0 so the bitrate is meaningless
+        self.settings.adaptive_bitrate = quality != StreamQuality::Lossless;
 
-        if let Err(e) = configure_encoder(&mut self.inner_builder) {
+        let encoder_config = EncoderConfig {
+            codec,
+            profile,
+            preset,
+            tuning_info,
+        };
+        if let Err(e) = encoder_config.configure(&mut self.inner_builder) {
             panic!("Error configuring encoder: {e}");
         }
 
-        let (width, height, texture_format) = {
+        let (width, height) = {
             let display_desc = screen_duplicator.desc();
             let mode_desc = &display_desc.ModeDesc;
-            (mode_desc.Width, mode_desc.Height, mode_desc.Format)
+            (mode_desc.Width, mode_desc.Height)
         };
+        // Not the display format since the duplicator can convert it
+        let texture_format = screen_duplicator.frame_format();
+        if !ENCODER_INPUT_FORMATS.contains(&texture_format) {
+            log::error!("Frame format {texture_format:?} can't be used as encoder input");
+            return;
+        }
 
         let (input, output) = match self.inner_builder.build(width, height, texture_format) {
             Ok((input, output)) => (input, output),
@@ -150,6 +189,7 @@ impl EncoderBuilder for NvidiaEncoderBuilder {
             screen_duplicator,
             input,
             output,
+            encoder_config,
             rtp_track,
             transceiver,
             ice_connection_state,
@@ -157,12 +197,58 @@ impl EncoderBuilder for NvidiaEncoderBuilder {
             payload_type,
             ssrc,
             codec_capability.clock_rate,
+            self.settings,
+            self.control,
+            self.shutdown,
         ));
     }
 }
 
+/// Settings of the encode session picked in `NvidiaEncoderBuilder::build`, kept so that a new
+/// session can be created when the old one fails or the display mode changes.
+pub struct EncoderConfig {
+    codec: nvenc::Codec,
+    profile: nvenc::CodecProfile,
+    preset: nvenc::EncodePreset,
+    tuning_info: nvenc::TuningInfo,
+}
+
+impl EncoderConfig {
+    /// Applies the settings that depend on the negotiated codec.
+    fn configure(
+        &self,
+        builder: &mut nvenc::EncoderBuilder<nvenc::DirectX11Device>,
+    ) -> nvenc::Result<()> {
+        builder
+            .with_codec_profile(self.profile)?
+            .with_encode_preset(self.preset)?
+            .with_tuning_info(self.tuning_info)?
+            .set_multi_pass(nvenc::MultiPassSetting::FullResolution)?;
+        Ok(())
+    }
+
+    /// Creates a new encode session from scratch, going through the same steps as
+    /// `NvidiaEncoderBuilder`.
+    pub fn build(
+        &self,
+        device: ID3D11Device,
+        width: u32,
+        height: u32,
+        texture_format: DXGI_FORMAT,
+    ) -> nvenc::Result<(
+        nvenc::EncoderInput<nvenc::DirectX11Device>,
+        nvenc::EncoderOutput,
+    )> {
+        let mut builder = nvenc::EncoderBuilder::new(device)?;
+        builder.repeat_csd(true)?;
+        builder.with_codec(self.codec)?;
+        self.configure(&mut builder)?;
+        builder.build(width, height, texture_format)
+    }
+}
+
 impl NvidiaEncoderBuilder {
-    pub fn new(id: String, stream_id: String) -> NvidiaEncoderBuilder {
+    pub fn new(id: String, stream_id: String, shutdown: ShutdownListener) -> NvidiaEncoderBuilder {
         log::info!("NvidiaEncoderBuilder::new");
         let device = match create_d3d11_device() {
             Ok(device) => device,
@@ -182,18 +268,18 @@ impl NvidiaEncoderBuilder {
         }
 
         let display_index = 0; // default to the first; could be changed later
-        let display_formats = vec![
-            DXGI_FORMAT_B8G8R8A8_UNORM,
-            DXGI_FORMAT_R10G10B10A2_UNORM,
-            DXGI_FORMAT_R8G8B8A8_UNORM,
-        ];
-        let supported_codecs = match list_supported_codecs(&mut inner_builder) {
+        let display_formats = select_display_formats(&device);
+        log::info!("Display formats: {display_formats:?}");
+        let quality = StreamQuality::Default;
+        let supported_codecs = match list_supported_codecs(&mut inner_builder, quality) {
             Ok(supported_codecs) => supported_codecs,
             Err(e) => {
                 panic!("Unable to list codecs: {e}");
             }
         };
 
+        let (handle, control) = encoder_handle();
+
         NvidiaEncoderBuilder {
             inner_builder,
             device,
@@ -202,36 +288,146 @@ impl NvidiaEncoderBuilder {
             display_index,
             display_formats,
             supported_codecs,
+            quality,
+            settings: EncoderSettings::default(),
+            handle,
+            control,
+            shutdown,
         }
     }
 
-    #[allow(dead_code)]
-    pub fn set_display_index(&mut self, display_index: u32) {
+    /// Returns a handle that controls the encoder once it is built.
+    pub fn handle(&self) -> EncoderHandle {
+        self.handle.clone()
+    }
+
+    pub fn set_display_index(&mut self, display_index: u32) -> &mut Self {
         self.display_index = display_index;
+        self
+    }
+
+    /// Sets whether the bitrate is capped by the REMB packets sent by the receiver.
+    pub fn set_remb_enabled(&mut self, use_remb: bool) -> &mut Self {
+        self.settings.use_remb = use_remb;
+        self
+    }
+
+    /// Sets the quality of the stream. `StreamQuality::Lossless` offers the H.264 High 4:4:4
+    /// profile first and only takes effect if the peer accepts it.
+    pub fn set_stream_quality(&mut self, quality: StreamQuality) -> &mut Self {
+        match list_supported_codecs(&mut self.inner_builder, quality) {
+            Ok(supported_codecs) => self.supported_codecs = supported_codecs,
+            Err(e) => log::error!("Unable to list codecs: {e}"),
+        }
+        self.quality = quality;
+        self
+    }
+
+    /// Sets the maximum framerate of the stream. Frames are dropped if the display refreshes
+    /// faster than this.
+    pub fn set_target_fps(&mut self, target_fps: u32) -> &mut Self {
+        assert!(target_fps > 0, "Target framerate must be non-zero");
+        self.settings.target_fps = target_fps;
+        self
+    }
+
+    /// Sets how long the screen has to stay unchanged before the last frame is sent again.
+    /// `None` disables the repetition.
+    pub fn set_idle_repeat_interval(
+        &mut self,
+        idle_repeat_interval: Option<Duration>,
+    ) -> &mut Self {
+        self.settings.idle_repeat_interval = idle_repeat_interval;
+        self
+    }
+
+    /// Sets how many times in a row the capture is reset on repeated errors before the stream is
+    /// stopped.
+    pub fn set_max_resets(&mut self, max_resets: u32) -> &mut Self {
+        self.settings.max_resets = max_resets;
+        self
+    }
+
+    /// Sets the minimum bitrate in bits per second, regardless of the bandwidth estimate.
+    pub fn set_min_bitrate(&mut self, min_bitrate: u32) -> &mut Self {
+        self.settings.min_bitrate = min_bitrate;
+        self
+    }
+
+    /// Sets the maximum bitrate in bits per second, regardless of the bandwidth estimate.
+    pub fn set_max_bitrate(&mut self, max_bitrate: u32) -> &mut Self {
+        self.settings.max_bitrate = max_bitrate;
+        self
+    }
+
+    /// Sets the bitrate in bits per second used before the first bandwidth estimate.
+    pub fn set_start_bitrate(&mut self, start_bitrate: Option<u32>) -> &mut Self {
+        self.settings.start_bitrate = start_bitrate;
+        self
+    }
+
+    /// Sets the size of the VBV buffer in bits. A smaller buffer caps the size of the keyframes
+    /// so that they don't cause latency spikes on slow links.
+    pub fn set_vbv_buffer_size(&mut self, vbv_buffer_size: Option<u32>) -> &mut Self {
+        self.settings.vbv_buffer_size = vbv_buffer_size;
+        self
+    }
+
+    /// Reserves bits per second of the bandwidth estimate for an audio track sent alongside the
+    /// video.
+    pub fn set_audio_bitrate(&mut self, audio_bitrate: u32) -> &mut Self {
+        self.settings.audio_bitrate = audio_bitrate;
+        self
+    }
+}
+
+/// Checks if an NVENC encoder can be created on the default GPU.
+pub fn is_encoder_available() -> bool {
+    match create_d3d11_device() {
+        Ok(device) => nvenc::EncoderBuilder::new(device).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Lists the encoder input formats that the device can create textures of.
+fn select_display_formats(device: &ID3D11Device) -> Vec<DXGI_FORMAT> {
+    filter_display_formats(&ENCODER_INPUT_FORMATS, |format| {
+        // SAFETY: Windows API call
+        match unsafe { device.CheckFormatSupport(format) } {
+            Ok(support) => support & D3D11_FORMAT_SUPPORT_TEXTURE2D.0 as u32 != 0,
+            Err(_) => false,
+        }
+    })
+}
+
+/// Keeps the formats in `preferred` that pass `is_supported`, falling back to 8-bit BGRA which
+/// desktop duplication always supports.
+fn filter_display_formats(
+    preferred: &[DXGI_FORMAT],
+    is_supported: impl Fn(DXGI_FORMAT) -> bool,
+) -> Vec<DXGI_FORMAT> {
+    let formats: Vec<DXGI_FORMAT> = preferred
+        .iter()
+        .copied()
+        .filter(|&format| is_supported(format))
+        .collect();
+    if formats.is_empty() {
+        vec![DXGI_FORMAT_B8G8R8A8_UNORM]
+    } else {
+        formats
     }
 }
 
 fn list_supported_codecs(
     inner_builder: &mut nvenc::EncoderBuilder<nvenc::DirectX11Device>,
+    quality: StreamQuality,
 ) -> nvenc::Result<Vec<Codec>> {
     let mut codecs: Vec<Codec> = Vec::new();
     for codec in inner_builder.supported_codecs()? {
         match codec {
             nvenc::Codec::H264 => {
-                let sorter = HashMap::from([
-                    (nvenc::CodecProfile::H264ConstrainedHigh, 0),
-                    (nvenc::CodecProfile::H264High, 1),
-                    (nvenc::CodecProfile::H264Main, 2),
-                    (nvenc::CodecProfile::H264Baseline, 3),
-                ]);
-
-                let mut supported_codec_profiles = inner_builder.supported_codec_profiles(codec)?;
-                supported_codec_profiles.sort_by(|a, b| match (sorter.get(a), sorter.get(b)) {
-                    (Some(x), Some(y)) => x.cmp(y),
-                    (None, Some(_)) => std::cmp::Ordering::Greater,
-                    (Some(_), None) => std::cmp::Ordering::Less,
-                    (None, None) => std::cmp::Ordering::Equal,
-                });
+                let supported_codec_profiles =
+                    offered_h264_profiles(inner_builder.supported_codec_profiles(codec)?, quality);
 
                 let convert_h264_profile = |profile: nvenc::CodecProfile| -> Option<H264Profile> {
                     match profile {
@@ -275,6 +471,90 @@ fn list_supported_codecs(
     Ok(codecs)
 }
 
+/// Orders the H.264 profiles from most to least preferred. High 4:4:4 is only offered for
+/// lossless streams, which can't be encoded with the other profiles. nvenc-rs can't set the
+/// chroma format so the stream would not have 4:4:4 chroma anyway.
+fn offered_h264_profiles(
+    mut profiles: Vec<nvenc::CodecProfile>,
+    quality: StreamQuality,
+) -> Vec<nvenc::CodecProfile> {
+    let lossless = quality == StreamQuality::Lossless;
+    if !lossless {
+        profiles.retain(|&profile| profile != nvenc::CodecProfile::H264High444);
+    }
+    sort_h264_profiles(&mut profiles, lossless);
+    profiles
+}
+
+/// Sorts the profiles from most to least preferred, with High 4:4:4 first if `high_444_first`.
+fn sort_h264_profiles(profiles: &mut [nvenc::CodecProfile], high_444_first: bool) {
+    let mut sorter = HashMap::from([
+        (nvenc::CodecProfile::H264ConstrainedHigh, 1),
+        (nvenc::CodecProfile::H264High, 2),
+        (nvenc::CodecProfile::H264Main, 3),
+        (nvenc::CodecProfile::H264Baseline, 4),
+    ]);
+    if high_444_first {
+        sorter.insert(nvenc::CodecProfile::H264High444, 0);
+    }
+
+    profiles.sort_by(|a, b| match (sorter.get(a), sorter.get(b)) {
+        (Some(x), Some(y)) => x.cmp(y),
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+}
+
+/// Returns the quality that the negotiated profile allows, falling back to
+/// `StreamQuality::Default` if lossless encoding is not possible. nvenc-rs has no capability query
+/// so the GPU support is only checked through the presets in `select_encode_settings`.
+fn effective_quality(requested: StreamQuality, profile: nvenc::CodecProfile) -> StreamQuality {
+    if requested == StreamQuality::Lossless && profile != nvenc::CodecProfile::H264High444 {
+        log::warn!("Lossless encoding needs the High 4:4:4 profile but got {profile:?}");
+        StreamQuality::Default
+    } else {
+        requested
+    }
+}
+
+/// Picks the encode preset and tuning for the given quality. Returns `None` if the encoder does
+/// not support the needed presets.
+fn select_encode_settings(
+    quality: StreamQuality,
+    supported_encode_presets: &[nvenc::EncodePreset],
+) -> Option<(nvenc::EncodePreset, nvenc::TuningInfo)> {
+    // P1 to P7 replaced the old presets and are combined with a tuning info
+    let new_settings = supported_encode_presets.contains(&nvenc::EncodePreset::P4);
+
+    match quality {
+        StreamQuality::Default => {
+            if new_settings {
+                // Equivalent settings for the old LowLatencyDefault and CBR_HQ:
+                // https://docs.nvidia.com/video-technologies/video-codec-sdk/nvenc-preset-migration-guide/
+                Some((nvenc::EncodePreset::P4, nvenc::TuningInfo::UltraLowLatency))
+            } else {
+                None
+            }
+        }
+        StreamQuality::Lossless => {
+            if new_settings {
+                // Fastest preset since the bitrate is already huge
+                Some((nvenc::EncodePreset::P1, nvenc::TuningInfo::Lossless))
+            } else if supported_encode_presets.contains(&nvenc::EncodePreset::LosslessHp) {
+                Some((nvenc::EncodePreset::LosslessHp, nvenc::TuningInfo::Lossless))
+            } else if supported_encode_presets.contains(&nvenc::EncodePreset::LosslessDefault) {
+                Some((
+                    nvenc::EncodePreset::LosslessDefault,
+                    nvenc::TuningInfo::Lossless,
+                ))
+            } else {
+                None
+            }
+        }
+    }
+}
+
 fn h264_profile_from_sdp_fmtp_line(sdp_fmtp_line: &str) -> Option<nvenc::CodecProfile> {
     if let Some((_, id)) = sdp_fmtp_line.split_once("profile-level-id=") {
         if id.len() >= 6 {
@@ -310,6 +590,27 @@ fn h264_profile_from_sdp_fmtp_line(sdp_fmtp_line: &str) -> Option<nvenc::CodecPr
     None
 }
 
+/// Level 1b in `NV_ENC_LEVEL`.
+const NV_ENC_LEVEL_H264_1B: u32 = 9;
+
+/// Returns the level of the `profile-level-id` in the fmtp line as an `NV_ENC_LEVEL`, which is the
+/// `level_idc` (e.g. 31 for level 3.1) except for level 1b.
+fn h264_level_from_sdp_fmtp_line(sdp_fmtp_line: &str) -> Option<u32> {
+    let (_, id) = sdp_fmtp_line.split_once("profile-level-id=")?;
+    let parse_byte = |range| u8::from_str_radix(id.get(range)?, 16).ok();
+    let profile_idc = parse_byte(0..2)?;
+    let profile_iop = parse_byte(2..4)?;
+    let level_idc = parse_byte(4..6)?;
+
+    // Baseline, Main and Extended signal level 1b as level 1.1 with constraint_set3_flag
+    let constraint_set3 = profile_iop & 0x10 != 0;
+    if level_idc == 11 && constraint_set3 && matches!(profile_idc, 66 | 77 | 88) {
+        Some(NV_ENC_LEVEL_H264_1B)
+    } else {
+        Some(level_idc as u32)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -351,4 +652,116 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn h264_ftmp_line_level_parsing() {
+        let test_cases = [
+            ("packetization-mode=1;profile-level-id=42001f", Some(31)),
+            ("packetization-mode=1;profile-level-id=42e01f", Some(31)),
+            ("profile-level-id=4d0028;packetization-mode=1", Some(40)),
+            ("packetization-mode=1;profile-level-id=640c33", Some(51)),
+            ("packetization-mode=1;profile-level-id=640c0b", Some(11)),
+            // Level 1b
+            ("packetization-mode=1;profile-level-id=42f00b", Some(9)),
+            ("packetization-mode=1;profile-level-id=42e00b", Some(11)),
+            ("packetization-mode=1;profile-level-id=640009", Some(9)),
+            ("level-asymmetry-allowed=1;profile-level-id=640c", None),
+            ("level-asymmetry-allowed=1;packetization-mode=1", None),
+        ];
+
+        for (sdp_fmtp_line, level) in test_cases {
+            assert_eq!(h264_level_from_sdp_fmtp_line(sdp_fmtp_line), level);
+        }
+    }
+
+    #[test]
+    fn lossless_encode_settings() {
+        let new_presets = [
+            nvenc::EncodePreset::P1,
+            nvenc::EncodePreset::P4,
+            nvenc::EncodePreset::P7,
+        ];
+        let old_presets = [nvenc::EncodePreset::LosslessDefault];
+
+        assert_eq!(
+            select_encode_settings(StreamQuality::Lossless, &new_presets),
+            Some((nvenc::EncodePreset::P1, nvenc::TuningInfo::Lossless))
+        );
+        assert_eq!(
+            select_encode_settings(StreamQuality::Lossless, &old_presets),
+            Some((
+                nvenc::EncodePreset::LosslessDefault,
+                nvenc::TuningInfo::Lossless
+            ))
+        );
+        assert_eq!(select_encode_settings(StreamQuality::Lossless, &[]), None);
+        assert_eq!(
+            select_encode_settings(StreamQuality::Default, &new_presets),
+            Some((nvenc::EncodePreset::P4, nvenc::TuningInfo::UltraLowLatency))
+        );
+    }
+
+    #[test]
+    fn lossless_fallback() {
+        let high_444 = nvenc::CodecProfile::H264High444;
+        let high = nvenc::CodecProfile::H264High;
+
+        assert_eq!(
+            effective_quality(StreamQuality::Lossless, high_444),
+            StreamQuality::Lossless
+        );
+        assert_eq!(
+            effective_quality(StreamQuality::Lossless, high),
+            StreamQuality::Default
+        );
+        assert_eq!(
+            effective_quality(StreamQuality::Default, high_444),
+            StreamQuality::Default
+        );
+    }
+
+    #[test]
+    fn display_format_selection() {
+        assert_eq!(
+            filter_display_formats(&ENCODER_INPUT_FORMATS, |_| true),
+            ENCODER_INPUT_FORMATS
+        );
+        assert_eq!(
+            filter_display_formats(&ENCODER_INPUT_FORMATS, |format| {
+                format != DXGI_FORMAT_B8G8R8A8_UNORM
+            }),
+            [DXGI_FORMAT_R10G10B10A2_UNORM, DXGI_FORMAT_R8G8B8A8_UNORM]
+        );
+        assert_eq!(
+            filter_display_formats(&ENCODER_INPUT_FORMATS, |_| false),
+            [DXGI_FORMAT_B8G8R8A8_UNORM]
+        );
+    }
+
+    #[test]
+    fn h264_profile_offer() {
+        let profiles = vec![
+            nvenc::CodecProfile::Autoselect,
+            nvenc::CodecProfile::H264Baseline,
+            nvenc::CodecProfile::H264Main,
+            nvenc::CodecProfile::H264High,
+            nvenc::CodecProfile::H264High444,
+            nvenc::CodecProfile::H264ConstrainedHigh,
+        ];
+
+        assert_eq!(
+            offered_h264_profiles(profiles.clone(), StreamQuality::Default),
+            [
+                nvenc::CodecProfile::H264ConstrainedHigh,
+                nvenc::CodecProfile::H264High,
+                nvenc::CodecProfile::H264Main,
+                nvenc::CodecProfile::H264Baseline,
+                nvenc::CodecProfile::Autoselect,
+            ]
+        );
+
+        let lossless = offered_h264_profiles(profiles, StreamQuality::Lossless);
+        assert_eq!(lossless[0], nvenc::CodecProfile::H264High444);
+        assert_eq!(lossless[1], nvenc::CodecProfile::H264ConstrainedHigh);
+    }
 }
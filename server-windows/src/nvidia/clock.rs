@@ -0,0 +1,120 @@
+use windows::Win32::System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency};
+
+/// Source of the timestamps of the captured frames.
+pub trait Clock {
+    /// Current time in ticks.
+    fn now(&self) -> u64;
+
+    /// Number of ticks per second.
+    fn frequency(&self) -> u64;
+}
+
+/// The clock DXGI uses for the present time of the frames.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PerformanceCounter;
+
+impl Clock for PerformanceCounter {
+    fn now(&self) -> u64 {
+        let mut timestamp = 0;
+        // SAFETY: Windows API call
+        unsafe {
+            QueryPerformanceCounter(&mut timestamp);
+        }
+        timestamp as u64
+    }
+
+    fn frequency(&self) -> u64 {
+        let mut frequency = 0;
+        // SAFETY: Windows API call
+        unsafe {
+            QueryPerformanceFrequency(&mut frequency);
+        }
+        frequency as u64
+    }
+}
+
+/// Converts the timestamps of a `Clock` to RTP timestamps.
+pub struct RtpTimestamp {
+    clock_rate: u32,
+    frequency: u64,
+    initial: u32,
+    /// Source timestamp of the first frame.
+    base: Option<u64>,
+}
+
+impl RtpTimestamp {
+    /// Creates a new `RtpTimestamp` that starts at `initial` and ticks at `clock_rate` Hz.
+    pub fn new(clock: &dyn Clock, clock_rate: u32, initial: u32) -> RtpTimestamp {
+        RtpTimestamp {
+            clock_rate,
+            frequency: clock.frequency(),
+            initial,
+            base: None,
+        }
+    }
+
+    /// Returns the RTP timestamp of a frame with the timestamp `source` from the clock.
+    ///
+    /// The frames can arrive out of presentation order when the encoder uses B-frames, so the
+    /// conversion is relative to the first frame instead of the previous one.
+    pub fn convert(&mut self, source: u64) -> u32 {
+        let base = *self.base.get_or_insert(source);
+        // Signed since a B-frame can be presented before the first frame that was output
+        let delta_source = source.wrapping_sub(base) as i64 as i128;
+        let delta = delta_source * self.clock_rate as i128 / self.frequency as i128;
+        // Truncating to `u32` is the same as adding modulo 2^32
+        self.initial.wrapping_add(delta as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockClock {
+        frequency: u64,
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> u64 {
+            0
+        }
+
+        fn frequency(&self) -> u64 {
+            self.frequency
+        }
+    }
+
+    #[test]
+    fn rtp_timestamp_conversion() {
+        let clock = MockClock {
+            frequency: 10_000_000,
+        };
+        let mut rtp_timestamp = RtpTimestamp::new(&clock, 90000, u32::MAX - 2000);
+
+        assert_eq!(rtp_timestamp.convert(5_000_000), u32::MAX - 2000);
+        // 1/60 of a second is 1500 ticks of the 90 kHz clock, minus the rounding error
+        assert_eq!(rtp_timestamp.convert(5_166_666), u32::MAX - 501);
+        // Wraps around
+        assert_eq!(rtp_timestamp.convert(5_333_333), 998);
+    }
+
+    #[test]
+    fn rtp_timestamp_reordering() {
+        let clock = MockClock {
+            frequency: 10_000_000,
+        };
+        let mut rtp_timestamp = RtpTimestamp::new(&clock, 90000, 1_000_000);
+
+        // I P B B in decode order, presented as I B B P
+        let decode_order = [0, 500_000, 166_667, 333_334];
+        let rtp_timestamps: Vec<u32> = decode_order
+            .iter()
+            .map(|&pts| rtp_timestamp.convert(10_000_000 + pts))
+            .collect();
+        assert_eq!(rtp_timestamps, [1_000_000, 1_004_500, 1_001_500, 1_003_000]);
+
+        // Presented before the first frame that was output
+        assert_eq!(rtp_timestamp.convert(10_000_000 - 166_667), 998_500);
+    }
+}
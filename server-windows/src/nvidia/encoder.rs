@@ -1,12 +1,36 @@
-use crate::capture::{AcquireFrameError, ScreenDuplicator};
-use std::sync::Arc;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use super::{
+    builder::{EncoderConfig, ENCODER_INPUT_FORMATS},
+    clock::{Clock, PerformanceCounter, RtpTimestamp},
+    handle::{EncoderCommand, EncoderControl, EncoderStatus},
+    nal::is_keyframe,
+    pacing::{FramePacer, IdleRepeat},
+    pause::{apply_pause_state, PauseTracker, Suspend},
+    queue::{EncodedFrame, FrameQueue},
+    stats::{FrameStats, OutputStats, Overrun, OverrunDetector},
+    watchdog::{Recovery, Watchdog},
+};
+use crate::{
+    capture::{AcquireFrameError, FrameCopy, ScreenDuplicator},
+    shutdown::ShutdownListener,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    watch,
+};
 use webrtc::{
     ice_transport::ice_connection_state::RTCIceConnectionState,
     rtcp::{
         self,
         payload_feedbacks::{
             full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
+            receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate,
         },
     },
     rtp::header::Header,
@@ -16,105 +40,725 @@ use webrtc::{
 use webrtc_helper::{
     codecs::H264SampleSender, interceptor::twcc::TwccBandwidthEstimate, peer::IceConnectionState,
 };
-use windows::Win32::System::Performance::QueryPerformanceFrequency;
+use windows::Win32::{
+    Foundation::RECT,
+    Graphics::{
+        Direct3D11::{ID3D11Texture2D, D3D11_TEXTURE2D_DESC},
+        Dxgi::Common::DXGI_FORMAT,
+    },
+};
 
 const RTP_MTU: usize = 1200;
 const RTCP_MAX_MTU: usize = 1500;
 const MIN_BITRATE_BPS: u32 = 64_000;
 const MAX_BITRATE_BPS: u32 = 100_000_000;
+const FRAME_QUEUE_CAPACITY: usize = 4;
+const MAX_CONSECUTIVE_ERRORS: u32 = 5;
+const RESET_BACKOFF: Duration = Duration::from_millis(100);
+const MIN_FPS: u32 = 15;
+const OVERRUN_WINDOW: u64 = 60;
+const MAX_DROP_PERCENT: u64 = 10;
+
+/// Settings of the encoder threads that are not negotiated through SDP.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct EncoderSettings {
+    /// Cap the bitrate with the REMB packets sent by the receiver.
+    pub use_remb: bool,
+    /// Update the encoder bitrate with the bandwidth estimate.
+    pub adaptive_bitrate: bool,
+    /// Maximum number of frames encoded per second.
+    pub target_fps: u32,
+    /// Encode the last frame again if the screen did not change for this long. Keeps the RTCP
+    /// feedback flowing while the desktop is static.
+    pub idle_repeat_interval: Option<Duration>,
+    /// Number of times in a row the capture is reset on repeated errors before giving up.
+    pub max_resets: u32,
+    /// Lower bound of the bitrate in bits per second.
+    pub min_bitrate: u32,
+    /// Upper bound of the bitrate in bits per second.
+    pub max_bitrate: u32,
+    /// Bitrate in bits per second until the first bandwidth estimate.
+    pub start_bitrate: Option<u32>,
+    /// Size of the VBV buffer in bits. Smaller buffers limit the size of keyframes at the cost of
+    /// their quality. `None` holds one frame at the current bitrate.
+    pub vbv_buffer_size: Option<u32>,
+    /// Bits per second of the bandwidth estimate reserved for the audio track.
+    pub audio_bitrate: u32,
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        EncoderSettings {
+            use_remb: true,
+            adaptive_bitrate: true,
+            target_fps: 60,
+            idle_repeat_interval: Some(Duration::from_millis(250)),
+            max_resets: 3,
+            min_bitrate: MIN_BITRATE_BPS,
+            max_bitrate: MAX_BITRATE_BPS,
+            start_bitrate: None,
+            vbv_buffer_size: None,
+            // No audio track is sent yet
+            audio_bitrate: 0,
+        }
+    }
+}
+
+/// Errors that can happen while encoding a frame.
+#[derive(Debug)]
+enum EncodeError {
+    NvEnc(nvenc::NvEncError),
+    Capture(AcquireFrameError),
+    FrameSizeMismatch {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    FrameFormatMismatch {
+        expected: DXGI_FORMAT,
+        actual: DXGI_FORMAT,
+    },
+    UnsupportedFrameFormat(DXGI_FORMAT),
+    Device(windows::core::Error),
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodeError::NvEnc(e) => write!(f, "{e}"),
+            EncodeError::Capture(e) => write!(f, "Failed to acquire frame: {e:?}"),
+            EncodeError::FrameSizeMismatch { expected, actual } => write!(
+                f,
+                "Frame is {}x{} but the encoder expects {}x{}",
+                actual.0, actual.1, expected.0, expected.1
+            ),
+            EncodeError::FrameFormatMismatch { expected, actual } => write!(
+                f,
+                "Frame is in {actual:?} but the encoder expects {expected:?}"
+            ),
+            EncodeError::UnsupportedFrameFormat(format) => {
+                write!(f, "Frame format {format:?} can't be used as encoder input")
+            }
+            EncodeError::Device(e) => write!(f, "Failed to get the D3D11 device: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+impl From<nvenc::NvEncError> for EncodeError {
+    fn from(e: nvenc::NvEncError) -> Self {
+        EncodeError::NvEnc(e)
+    }
+}
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 enum RtcpEvent {
     Pli,
     Fir,
+    /// Receiver estimated maximum bitrate in bits per second
+    Remb(u32),
 }
 
 struct NvidiaEncoderInput {
     screen_duplicator: ScreenDuplicator,
     input: nvenc::EncoderInput<nvenc::DirectX11Device>,
+    /// Creates a new encode session when the old one has to be replaced.
+    encoder_config: EncoderConfig,
+    /// Hands the output of a new encode session over to the output thread.
+    output_tx: mpsc::Sender<nvenc::EncoderOutput>,
     bandwidth_estimate: TwccBandwidthEstimate,
     frame_rate_num: u32,
     frame_rate_den: u32,
+    frame_size: (u32, u32),
+    frame_format: DXGI_FORMAT,
     rtcp_rx: UnboundedReceiver<RtcpEvent>,
+    command_rx: UnboundedReceiver<EncoderCommand>,
+    status_tx: watch::Sender<EncoderStatus>,
+    frame_queue: Arc<FrameQueue>,
+    remb_bitrate: Option<u32>,
+    bitrate_override: Option<u32>,
+    bitrate: u32,
+    min_bitrate: u32,
+    max_bitrate: u32,
+    vbv_buffer_size: Option<u32>,
+    audio_bitrate: u32,
+    adaptive_bitrate: bool,
+    frame_pacer: FramePacer,
+    target_fps: u32,
+    max_fps: u32,
+    frame_stats: FrameStats,
+    /// Updated by the output thread as the frames are written.
+    output_stats: Arc<Mutex<OutputStats>>,
+    overrun_detector: OverrunDetector,
+    pause_tracker: PauseTracker,
+    acquire_timeout_millis: u32,
+    idle_repeat: Option<IdleRepeat>,
+    last_frame: Option<FrameCopy>,
+    /// The pacing skipped the frame in `last_frame` and it has not been encoded since.
+    skipped_frame: bool,
+    clock: Box<dyn Clock + Send>,
 }
 
 impl NvidiaEncoderInput {
     fn new(
         screen_duplicator: ScreenDuplicator,
         input: nvenc::EncoderInput<nvenc::DirectX11Device>,
+        encoder_config: EncoderConfig,
+        output_tx: mpsc::Sender<nvenc::EncoderOutput>,
         bandwidth_estimate: TwccBandwidthEstimate,
         rtcp_rx: UnboundedReceiver<RtcpEvent>,
+        frame_queue: Arc<FrameQueue>,
+        output_stats: Arc<Mutex<OutputStats>>,
+        settings: &EncoderSettings,
+        control: EncoderControl,
+        clock: Box<dyn Clock + Send>,
     ) -> NvidiaEncoderInput {
-        let (frame_rate_num, frame_rate_den) = {
+        let (frame_rate_num, frame_rate_den, frame_size) = {
             let display_desc = screen_duplicator.desc();
+            let mode_desc = &display_desc.ModeDesc;
             (
-                display_desc.ModeDesc.RefreshRate.Numerator,
-                display_desc.ModeDesc.RefreshRate.Numerator,
+                mode_desc.RefreshRate.Numerator,
+                mode_desc.RefreshRate.Denominator,
+                (mode_desc.Width, mode_desc.Height),
             )
         };
 
+        let frame_format = screen_duplicator.frame_format();
+
+        let frame_interval = Duration::from_secs(1) / settings.target_fps;
+        let idle_repeat = settings
+            .idle_repeat_interval
+            .map(|repeat_interval| IdleRepeat::new(repeat_interval, frame_interval));
+
         NvidiaEncoderInput {
             screen_duplicator,
             input,
+            encoder_config,
+            output_tx,
             bandwidth_estimate,
             frame_rate_num,
             frame_rate_den,
+            frame_size,
+            frame_format,
             rtcp_rx,
+            command_rx: control.command_rx,
+            status_tx: control.status_tx,
+            frame_queue,
+            remb_bitrate: None,
+            bitrate_override: None,
+            bitrate: 0,
+            min_bitrate: settings.min_bitrate,
+            max_bitrate: settings.max_bitrate,
+            vbv_buffer_size: settings.vbv_buffer_size,
+            audio_bitrate: settings.audio_bitrate,
+            adaptive_bitrate: settings.adaptive_bitrate,
+            frame_pacer: FramePacer::new(settings.target_fps, clock.frequency()),
+            target_fps: settings.target_fps,
+            max_fps: settings.target_fps,
+            frame_stats: FrameStats::default(),
+            output_stats,
+            overrun_detector: OverrunDetector::new(OVERRUN_WINDOW, MAX_DROP_PERCENT),
+            pause_tracker: PauseTracker::new(control.pause_handle),
+            acquire_timeout_millis: frame_interval.as_millis().max(1) as u32,
+            idle_repeat,
+            last_frame: None,
+            skipped_frame: false,
+            clock,
         }
     }
 
     fn update_bitrate(&mut self) {
-        let bitrate = self.bandwidth_estimate.borrow().bits_per_sec() as u32;
-        let bitrate = bitrate.clamp(MIN_BITRATE_BPS, MAX_BITRATE_BPS);
-        // Divide first to prevent overflow
-        let vbv_buffer_size = bitrate / self.frame_rate_num * self.frame_rate_den;
-        if let Err(e) = self
-            .input
-            .update_average_bitrate(bitrate, Some(vbv_buffer_size))
-        {
+        if !self.adaptive_bitrate {
+            return;
+        }
+
+        let bitrate = match self.bitrate_override {
+            Some(bitrate) => bitrate,
+            None => {
+                let bitrate = self.bandwidth_estimate.borrow().bits_per_sec() as u32;
+                // REMB is only used as an upper bound to the TWCC estimate
+                let bitrate = match self.remb_bitrate {
+                    Some(remb_bitrate) => bitrate.min(remb_bitrate),
+                    None => bitrate,
+                };
+                video_bitrate(bitrate, self.audio_bitrate, self.min_bitrate)
+            }
+        };
+        self.set_bitrate(bitrate);
+    }
+
+    fn set_bitrate(&mut self, bitrate: u32) {
+        let rate_control = RateControl::new(
+            bitrate,
+            self.min_bitrate,
+            self.max_bitrate,
+            self.vbv_buffer_size,
+            (self.frame_rate_num, self.frame_rate_den),
+        );
+        if let Err(e) = self.input.update_average_bitrate(
+            rate_control.average_bitrate,
+            Some(rate_control.vbv_buffer_size),
+        ) {
             log::error!("Error trying to update bitrate: {e}");
+        } else {
+            self.bitrate = rate_control.average_bitrate;
+        }
+    }
+
+    fn handle_command(&mut self, command: EncoderCommand) {
+        match command {
+            EncoderCommand::ForceIdr => self.input.force_idr_on_next(),
+            EncoderCommand::SetBitrate(bitrate) => {
+                self.bitrate_override = bitrate;
+                self.update_bitrate();
+            }
+        }
+    }
+
+    /// Makes the current state visible to the `EncoderHandle`.
+    fn publish_status(&self) {
+        self.status_tx.send_replace(EncoderStatus {
+            bitrate: self.bitrate,
+            resolution: self.frame_size,
+            target_fps: self.target_fps,
+            stats: self.stats(),
+        });
+    }
+
+    /// Returns the frame counts since the stream started.
+    fn stats(&self) -> FrameStats {
+        FrameStats {
+            dropped: self.frame_queue.dropped(),
+            output: *self.output_stats.lock().unwrap(),
+            ..self.frame_stats
         }
     }
 
-    fn encode(&mut self) -> Result<(), nvenc::NvEncError> {
-        match self.screen_duplicator.acquire_frame(4294967295u32) {
+    /// Lowers the framerate while the RTP writer is dropping frames and raises it back once it
+    /// catches up.
+    fn adapt_framerate(&mut self) {
+        let stats = self.stats();
+        let target_fps = match self.overrun_detector.update(stats) {
+            Overrun::Detected => (self.target_fps / 2).max(MIN_FPS.min(self.max_fps)),
+            Overrun::Cleared => (self.target_fps * 2).min(self.max_fps),
+            Overrun::None => return,
+        };
+        if target_fps != self.target_fps {
+            log::info!("Changing the framerate to {target_fps}, {stats:?}");
+            self.target_fps = target_fps;
+            self.frame_pacer = FramePacer::new(target_fps, self.clock.frequency());
+        }
+    }
+
+    fn encode(&mut self) -> Result<(), EncodeError> {
+        let pause_state = self.pause_tracker.poll();
+        if !apply_pause_state(pause_state, self) {
+            return Ok(());
+        }
+
+        self.adapt_framerate();
+
+        if self.frame_queue.take_keyframe_request() {
+            self.input.force_idr_on_next();
+        }
+
+        match self
+            .screen_duplicator
+            .acquire_frame(self.acquire_timeout_millis)
+        {
             Ok((acquired_image, info)) => {
                 let timestamp = info.LastPresentTime as u64;
-                // Check if image was updated
-                if timestamp != 0 {
+                // Only the pointer changed
+                if timestamp == 0 {
+                    drop(acquired_image);
+                    return self.repeat_idle_frame();
+                }
+                self.frame_stats.acquired += 1;
+
+                let changed_rects = match acquired_image.changed_rects() {
+                    Ok(rects) => Some(rects),
+                    Err(e) => {
+                        log::warn!("Failed to get the changed regions: {e}");
+                        None
+                    }
+                };
+                if !has_new_image(changed_rects.as_deref()) {
+                    drop(acquired_image);
+                    return self.repeat_idle_frame();
+                }
+                let encode_now = self.frame_pacer.should_encode(timestamp);
+                // The copy has to follow every update, even the dropped ones, since only the
+                // changed regions are copied into it
+                if self.idle_repeat.is_some() {
+                    save_frame(
+                        &mut self.last_frame,
+                        acquired_image.as_ref(),
+                        changed_rects.as_deref(),
+                    );
+                } else if !encode_now {
+                    // Only the skipped frames are kept so the whole frame is copied
+                    save_frame(&mut self.last_frame, acquired_image.as_ref(), None);
+                }
+                self.skipped_frame = !encode_now;
+
+                if encode_now {
+                    check_frame(self.frame_size, self.frame_format, acquired_image.as_ref())?;
+                    if let Some(idle_repeat) = &mut self.idle_repeat {
+                        idle_repeat.reset();
+                    }
                     self.input.encode_frame(acquired_image, timestamp)?;
+                    self.frame_stats.encoded += 1;
                 }
                 Ok(())
             }
             Err(e) => match e {
-                AcquireFrameError::Retry => Ok(()),
-                AcquireFrameError::Unknown => panic!("{:?}", e),
+                AcquireFrameError::Retry => self.repeat_idle_frame(),
+                AcquireFrameError::Unknown => Err(EncodeError::Capture(e)),
             },
         }
     }
+
+    /// Encodes the frame skipped by the pacing once the screen stops changing, or the last frame
+    /// again if the screen has not changed for long enough.
+    fn repeat_idle_frame(&mut self) -> Result<(), EncodeError> {
+        if let Some(last_frame) = &self.last_frame {
+            if idle_frame_due(self.skipped_frame, self.idle_repeat.as_mut()) {
+                self.skipped_frame = false;
+                self.input
+                    .encode_frame(last_frame.clone(), self.clock.now())?;
+                self.frame_stats.encoded += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a new encode session for the current display mode and hands its output over to
+    /// the output thread.
+    fn rebuild_encoder(&mut self) -> Result<(), EncodeError> {
+        let mode_desc = self.screen_duplicator.desc().ModeDesc;
+        let frame_format = self.screen_duplicator.frame_format();
+        let device = self
+            .screen_duplicator
+            .d3d11_device()
+            .map_err(EncodeError::Device)?;
+        let (input, output) =
+            self.encoder_config
+                .build(device, mode_desc.Width, mode_desc.Height, frame_format)?;
+
+        // The output thread switches over once the old session is torn down
+        if self.output_tx.send(output).is_err() {
+            log::warn!("Output thread has already exited");
+        }
+        self.input = input;
+
+        self.frame_size = (mode_desc.Width, mode_desc.Height);
+        self.frame_format = frame_format;
+        self.frame_rate_num = mode_desc.RefreshRate.Numerator;
+        self.frame_rate_den = mode_desc.RefreshRate.Denominator;
+        // The new session starts with the bitrate it was built with
+        if self.adaptive_bitrate && self.bitrate != 0 {
+            self.set_bitrate(self.bitrate);
+        }
+        Ok(())
+    }
+}
+
+impl Suspend for NvidiaEncoderInput {
+    fn suspend(&mut self) {
+        log::info!("Stream paused");
+        // Nothing is captured until the stream is resumed
+        self.screen_duplicator.release_output_duplicator();
+        self.last_frame = None;
+        self.skipped_frame = false;
+    }
+
+    fn resume(&mut self) {
+        log::info!("Stream resumed");
+        if let Err(e) = self.screen_duplicator.reset_output_duplicator() {
+            log::error!("Failed to recreate the output duplicator: {e}");
+        }
+        self.frame_pacer = FramePacer::new(self.target_fps, self.clock.frequency());
+        self.input.force_idr_on_next();
+    }
+}
+
+/// Parts of the input thread that are restarted to recover from encode errors.
+trait Recover {
+    /// Recreates the capture and the encode session then starts over from a keyframe.
+    fn reset(&mut self);
+    /// Recreates the encode session after the display mode changed.
+    fn reconfigure(&mut self) -> Result<(), EncodeError>;
+}
+
+impl Recover for NvidiaEncoderInput {
+    fn reset(&mut self) {
+        if let Err(e) = self.screen_duplicator.reset_output_duplicator() {
+            log::error!("Failed to reset the output duplicator: {e}");
+        }
+        if let Err(e) = self.rebuild_encoder() {
+            log::error!("Failed to rebuild the encoder: {e}");
+        }
+        self.frame_pacer = FramePacer::new(self.target_fps, self.clock.frequency());
+        self.last_frame = None;
+        self.skipped_frame = false;
+        self.input.force_idr_on_next();
+    }
+
+    fn reconfigure(&mut self) -> Result<(), EncodeError> {
+        self.rebuild_encoder()?;
+        self.last_frame = None;
+        self.skipped_frame = false;
+        Ok(())
+    }
+}
+
+/// Lets the `watchdog` decide what to do with the result of an encode. Returns false if the
+/// stream can't recover and should be stopped.
+async fn recover(
+    result: Result<(), EncodeError>,
+    watchdog: &mut Watchdog,
+    encoder: &mut impl Recover,
+) -> bool {
+    let e = match result {
+        Ok(_) => {
+            watchdog.on_success();
+            return true;
+        }
+        // A new display mode is not a fault
+        Err(
+            e @ EncodeError::FrameSizeMismatch {
+                actual: (1.., 1..), ..
+            }
+            | e @ EncodeError::FrameFormatMismatch { .. },
+        ) => {
+            log::info!("{e}, rebuilding the encoder");
+            match encoder.reconfigure() {
+                Ok(_) => return true,
+                Err(e) => e,
+            }
+        }
+        Err(e) => e,
+    };
+    log::error!("Error encoding: {e}");
+    match watchdog.on_error() {
+        Recovery::Ignore => true,
+        Recovery::Reset(backoff) => {
+            log::warn!(
+                "Resetting capture after repeated errors ({} so far)",
+                watchdog.total_resets()
+            );
+            tokio::time::sleep(backoff).await;
+            encoder.reset();
+            true
+        }
+        Recovery::GiveUp => {
+            log::error!("Capture could not recover from errors");
+            false
+        }
+    }
+}
+
+/// Rate control parameters of the encoder, in bits and bits per second.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct RateControl {
+    average_bitrate: u32,
+    /// Bounds the size of the keyframes.
+    vbv_buffer_size: u32,
+}
+
+impl RateControl {
+    /// Clamps `bitrate` between the limits. The VBV buffer holds one frame at that bitrate unless
+    /// its size is given.
+    fn new(
+        bitrate: u32,
+        min_bitrate: u32,
+        max_bitrate: u32,
+        vbv_buffer_size: Option<u32>,
+        (frame_rate_num, frame_rate_den): (u32, u32),
+    ) -> RateControl {
+        let average_bitrate = clamp_bitrate(bitrate, min_bitrate, max_bitrate);
+        RateControl {
+            average_bitrate,
+            vbv_buffer_size: vbv_buffer_size.unwrap_or_else(|| {
+                single_frame_vbv_size(average_bitrate, frame_rate_num, frame_rate_den)
+            }),
+        }
+    }
+}
+
+/// Not `clamp` so that a maximum below the minimum wins instead of panicking.
+fn clamp_bitrate(bitrate: u32, min_bitrate: u32, max_bitrate: u32) -> u32 {
+    bitrate.max(min_bitrate).min(max_bitrate)
+}
+
+/// Reserves `audio_bitrate` of the `total` estimate for audio and gives the rest to video. Video
+/// still gets `min_bitrate` if the estimate is below the audio reservation.
+fn video_bitrate(total: u32, audio_bitrate: u32, min_bitrate: u32) -> u32 {
+    total.saturating_sub(audio_bitrate).max(min_bitrate)
+}
+
+/// Number of bits in one frame at the given bitrate and framerate.
+fn single_frame_vbv_size(bitrate: u32, frame_rate_num: u32, frame_rate_den: u32) -> u32 {
+    let vbv_buffer_size = bitrate as u64 * frame_rate_den as u64 / frame_rate_num.max(1) as u64;
+    vbv_buffer_size.min(u32::MAX as u64) as u32
+}
+
+/// Checks that the frame has the dimensions and format the encoder was initialized with.
+fn check_frame(
+    expected_size: (u32, u32),
+    expected_format: DXGI_FORMAT,
+    frame: &ID3D11Texture2D,
+) -> Result<(), EncodeError> {
+    let mut desc = D3D11_TEXTURE2D_DESC::default();
+    // SAFETY: Windows API call
+    unsafe { frame.GetDesc(&mut desc) };
+    check_frame_desc(expected_size, expected_format, &desc)
+}
+
+fn check_frame_desc(
+    expected_size: (u32, u32),
+    expected_format: DXGI_FORMAT,
+    desc: &D3D11_TEXTURE2D_DESC,
+) -> Result<(), EncodeError> {
+    let actual = (desc.Width, desc.Height);
+    if actual != expected_size || actual.0 == 0 || actual.1 == 0 {
+        return Err(EncodeError::FrameSizeMismatch {
+            expected: expected_size,
+            actual,
+        });
+    }
+    if !ENCODER_INPUT_FORMATS.contains(&desc.Format) {
+        return Err(EncodeError::UnsupportedFrameFormat(desc.Format));
+    }
+    if desc.Format != expected_format {
+        return Err(EncodeError::FrameFormatMismatch {
+            expected: expected_format,
+            actual: desc.Format,
+        });
+    }
+    Ok(())
+}
+
+/// Returns false if the frame was presented without changing any pixels. Frames with unknown
+/// changes are assumed to be new.
+fn has_new_image(changed_rects: Option<&[RECT]>) -> bool {
+    changed_rects.map_or(true, |rects| !rects.is_empty())
+}
+
+/// Returns true if the last frame should be encoded while the screen is not changing. A frame
+/// skipped by the pacing is encoded on the first timeout, otherwise the frame is repeated every
+/// `idle_repeat` interval.
+fn idle_frame_due(skipped_frame: bool, idle_repeat: Option<&mut IdleRepeat>) -> bool {
+    match idle_repeat {
+        Some(idle_repeat) if skipped_frame => {
+            idle_repeat.reset();
+            true
+        }
+        Some(idle_repeat) => idle_repeat.on_timeout(),
+        None => skipped_frame,
+    }
+}
+
+/// Keeps a copy of `frame` to be repeated when the screen is idle. Only the `changed_rects` are
+/// copied into an existing copy, or the whole frame if they are unknown.
+fn save_frame(
+    last_frame: &mut Option<FrameCopy>,
+    frame: &ID3D11Texture2D,
+    changed_rects: Option<&[RECT]>,
+) {
+    if let Some(frame_copy) = last_frame {
+        let copied = match changed_rects {
+            Some(rects) => frame_copy.copy_rects_from(frame, rects),
+            None => frame_copy.copy_from(frame),
+        };
+        if copied {
+            return;
+        }
+    }
+    // Either the first frame or the resolution changed
+    match FrameCopy::new(frame) {
+        Ok(frame_copy) => *last_frame = Some(frame_copy),
+        Err(e) => {
+            log::error!("Failed to copy frame: {e}");
+            *last_frame = None;
+        }
+    }
 }
 
 struct NvidiaEncoderOutput {
     output: nvenc::EncoderOutput,
+    frame_queue: Arc<FrameQueue>,
+    output_stats: Arc<Mutex<OutputStats>>,
+    rtp_timestamp: RtpTimestamp,
+}
+
+impl NvidiaEncoderOutput {
+    fn new(
+        output: nvenc::EncoderOutput,
+        frame_queue: Arc<FrameQueue>,
+        output_stats: Arc<Mutex<OutputStats>>,
+        clock: &dyn Clock,
+        clock_rate: u32,
+    ) -> NvidiaEncoderOutput {
+        NvidiaEncoderOutput {
+            output,
+            frame_queue,
+            output_stats,
+            rtp_timestamp: RtpTimestamp::new(clock, clock_rate, rand::random::<u32>()),
+        }
+    }
+
+    fn write_packets(&mut self) -> Result<(), nvenc::NvEncError> {
+        let encode_result = self.output.wait_for_output(|lock| {
+            let slice = unsafe {
+                std::slice::from_raw_parts(
+                    lock.bitstreamBufferPtr as *const u8,
+                    lock.bitstreamSizeInBytes as usize,
+                )
+            };
+
+            let timestamp = self.rtp_timestamp.convert(lock.outputTimeStamp);
+            let is_keyframe = is_keyframe(slice);
+            log::trace!(
+                "Encoded frame {}: {} bytes, keyframe: {is_keyframe}, average QP: {}",
+                lock.frameIdx,
+                slice.len(),
+                lock.frameAvgQP
+            );
+            self.output_stats.lock().unwrap().record(
+                lock.bitstreamSizeInBytes,
+                is_keyframe,
+                lock.frameAvgQP,
+            );
+
+            // Copied out so that the bitstream can be unlocked while the frame waits to be sent
+            self.frame_queue.push(EncodedFrame {
+                data: slice.to_vec(),
+                timestamp,
+                is_keyframe,
+            });
+        });
+
+        encode_result
+    }
+}
+
+/// Packetizes the frames from the `FrameQueue` and writes them to the RTP track.
+struct RtpWriter {
+    frame_queue: Arc<FrameQueue>,
     rtp_track: Arc<TrackLocalStaticRTP>,
     payloader: H264SampleSender,
     header: Header,
-    clock_rate: u32,
-    timer_frequency: u64,
-    timestamp: u32,
-    prev_timestamp_source: Option<u64>,
 }
 
-impl NvidiaEncoderOutput {
+impl RtpWriter {
     fn new(
-        output: nvenc::EncoderOutput,
+        frame_queue: Arc<FrameQueue>,
         rtp_track: Arc<TrackLocalStaticRTP>,
         payload_type: u8,
         ssrc: u32,
-        clock_rate: u32,
-    ) -> NvidiaEncoderOutput {
+    ) -> RtpWriter {
         let payloader = H264SampleSender::default();
-        let timer_frequency = timer_frequency();
         let header = Header {
             version: 2,
             padding: false,
@@ -126,54 +770,35 @@ impl NvidiaEncoderOutput {
             ..Default::default()
         };
 
-        NvidiaEncoderOutput {
-            output,
+        RtpWriter {
+            frame_queue,
             rtp_track,
             payloader,
             header,
-            clock_rate,
-            timer_frequency,
-            timestamp: rand::random::<u32>(),
-            prev_timestamp_source: None,
         }
     }
 
-    fn write_packets(&mut self, handle: &tokio::runtime::Handle) -> Result<(), nvenc::NvEncError> {
-        let encode_result = self.output.wait_for_output(|lock| {
-            let slice = unsafe {
-                std::slice::from_raw_parts(
-                    lock.bitstreamBufferPtr as *const u8,
-                    lock.bitstreamSizeInBytes as usize,
-                )
-            };
-
-            // This conversion is chosen even though it causes the timestamp to be prone to drift
-            // because only accurate frame intervals are important.
-            if let Some(prev) = self.prev_timestamp_source {
-                let delta_source = lock.outputTimeStamp.wrapping_sub(prev);
-                let delta =
-                    delta_source.wrapping_mul(self.clock_rate as u64) / self.timer_frequency;
-                // Accumulates small errors coming from `delta`. Can cause the timestamp to drift
-                // from the source's timestamp.
-                self.timestamp = self.timestamp.wrapping_add(delta as u32);
-            }
-            self.prev_timestamp_source = Some(lock.outputTimeStamp);
-
-            self.header.timestamp = self.timestamp;
+    async fn run(mut self) {
+        while let Some(frame) = self.frame_queue.pop().await {
+            self.header.timestamp = frame.timestamp;
+            log::trace!("Frames waiting to be sent: {}", self.frame_queue.depth());
 
             // Send the encoded frames
-            let write_result = handle.block_on(async {
-                self.payloader
-                    .send_payload(RTP_MTU - 12, &mut self.header, slice, &*self.rtp_track)
-                    .await
-            });
+            let write_result = self
+                .payloader
+                .send_payload(
+                    RTP_MTU - 12,
+                    &mut self.header,
+                    &frame.data,
+                    &*self.rtp_track,
+                )
+                .await;
 
             if let Err(e) = write_result {
                 log::error!("Error writing RTP: {e}");
             }
-        });
-
-        encode_result
+        }
+        log::info!("RTP writer exited");
     }
 }
 
@@ -182,6 +807,7 @@ async fn rtcp_handler(
     mut ice_connection_state: IceConnectionState,
     rtcp_tx: UnboundedSender<RtcpEvent>,
     ssrc: u32,
+    use_remb: bool,
 ) {
     if let Some(sender) = transceiver.sender().await {
         let mut buf = vec![0u8; RTCP_MAX_MTU];
@@ -195,23 +821,9 @@ async fn rtcp_handler(
                 }
                 read_result = sender.read(&mut buf) => {
                     if let Ok((n, _)) = read_result {
-                        let mut raw_data = &buf[..n];
-                        if let Ok(packets) = rtcp::packet::unmarshal(&mut raw_data) {
-                            for packet in packets {
-                                let packet = packet.as_any();
-                                if let Some(pli) = packet.downcast_ref::<PictureLossIndication>() {
-                                    if pli.media_ssrc == ssrc {
-                                        if let Err(e) = rtcp_tx.send(RtcpEvent::Pli) {
-                                            log::warn!("Error while sending RtcpEvent: {e}");
-                                        }
-                                    }
-                                } else if let Some(fir) = packet.downcast_ref::<FullIntraRequest>() {
-                                    if fir.media_ssrc == ssrc {
-                                        if let Err(e) = rtcp_tx.send(RtcpEvent::Fir) {
-                                            log::warn!("Error while sending RtcpEvent: {e}");
-                                        }
-                                    }
-                                }
+                        for event in parse_rtcp_events(&buf[..n], ssrc, use_remb) {
+                            if let Err(e) = rtcp_tx.send(event) {
+                                log::warn!("Error while sending RtcpEvent: {e}");
                             }
                         }
                     } else {
@@ -225,10 +837,35 @@ async fn rtcp_handler(
     log::info!("RTCP handler exited");
 }
 
+/// Extracts the `RtcpEvent`s concerning `ssrc` from a compound RTCP packet.
+fn parse_rtcp_events(mut raw_data: &[u8], ssrc: u32, use_remb: bool) -> Vec<RtcpEvent> {
+    let mut events = Vec::new();
+    if let Ok(packets) = rtcp::packet::unmarshal(&mut raw_data) {
+        for packet in packets {
+            let packet = packet.as_any();
+            if let Some(pli) = packet.downcast_ref::<PictureLossIndication>() {
+                if pli.media_ssrc == ssrc {
+                    events.push(RtcpEvent::Pli);
+                }
+            } else if let Some(fir) = packet.downcast_ref::<FullIntraRequest>() {
+                if fir.media_ssrc == ssrc {
+                    events.push(RtcpEvent::Fir);
+                }
+            } else if let Some(remb) = packet.downcast_ref::<ReceiverEstimatedMaximumBitrate>() {
+                if use_remb && remb.ssrcs.contains(&ssrc) {
+                    events.push(RtcpEvent::Remb(remb.bitrate as u32));
+                }
+            }
+        }
+    }
+    events
+}
+
 pub async fn start_encoder(
     screen_duplicator: ScreenDuplicator,
     input: nvenc::EncoderInput<nvenc::DirectX11Device>,
     output: nvenc::EncoderOutput,
+    encoder_config: EncoderConfig,
     rtp_track: Arc<TrackLocalStaticRTP>,
     transceiver: Arc<RTCRtpTransceiver>,
     mut ice_connection_state: IceConnectionState,
@@ -236,6 +873,9 @@ pub async fn start_encoder(
     payload_type: u8,
     ssrc: u32,
     clock_rate: u32,
+    settings: EncoderSettings,
+    control: EncoderControl,
+    shutdown: ShutdownListener,
 ) {
     while *ice_connection_state.borrow() != RTCIceConnectionState::Connected {
         if let Err(_) = ice_connection_state.changed().await {
@@ -252,24 +892,87 @@ pub async fn start_encoder(
         ice_connection_state.clone(),
         rtcp_tx,
         ssrc,
+        settings.use_remb,
     ));
 
-    let mut input = NvidiaEncoderInput::new(screen_duplicator, input, bandwidth_estimate, rtcp_rx);
-    let mut output = NvidiaEncoderOutput::new(output, rtp_track, payload_type, ssrc, clock_rate);
+    let frame_queue = Arc::new(FrameQueue::new(FRAME_QUEUE_CAPACITY));
+    let (output_tx, output_rx) = mpsc::channel();
+    let output_stats = Arc::new(Mutex::new(OutputStats::default()));
+
+    let mut input = NvidiaEncoderInput::new(
+        screen_duplicator,
+        input,
+        encoder_config,
+        output_tx,
+        bandwidth_estimate,
+        rtcp_rx,
+        frame_queue.clone(),
+        output_stats.clone(),
+        &settings,
+        control,
+        Box::new(PerformanceCounter),
+    );
+    if let Some(start_bitrate) = settings.start_bitrate {
+        if settings.adaptive_bitrate {
+            input.set_bitrate(start_bitrate);
+        }
+    }
+    let mut output = NvidiaEncoderOutput::new(
+        output,
+        frame_queue.clone(),
+        output_stats,
+        &PerformanceCounter,
+        clock_rate,
+    );
+    let rtp_writer = RtpWriter::new(frame_queue, rtp_track, payload_type, ssrc);
+
+    tokio::spawn(rtp_writer.run());
 
     let ice_1 = ice_connection_state;
     let ice_2 = ice_1.clone();
+    let mut shutdown_1 = shutdown;
+    let shutdown_2 = shutdown_1.clone();
+    let stream_ended_1 = Arc::new(AtomicBool::new(false));
+    let stream_ended_2 = stream_ended_1.clone();
+
+    let output_thread = std::thread::spawn(move || {
+        while *ice_2.borrow() == RTCIceConnectionState::Connected && !shutdown_2.is_requested() {
+            if let Err(e) = output.write_packets() {
+                // The input thread drops the old session after sending the one replacing it
+                if let Ok(new_output) = output_rx.try_recv() {
+                    output.output = new_output;
+                    continue;
+                }
+                // The wait is interrupted by the end of stream sent by the input thread
+                if !stream_ended_2.load(Ordering::Acquire) {
+                    log::error!("Error while waiting for output: {e}");
+                }
+                break;
+            }
+        }
+        output.frame_queue.close();
+        log::info!("Output thread exited");
+    });
 
     tokio::spawn(tokio::task::unconstrained(async move {
-        // TODO: Frame interval should be configurable and/or signaled in SDP
-        let mut interval = tokio::time::interval(std::time::Duration::from_nanos(16_666_667));
+        // TODO: Frame interval should be signaled in SDP
+        let frame_interval = Duration::from_secs(1) / settings.target_fps;
+        let mut interval = tokio::time::interval(frame_interval);
+        let mut watchdog =
+            Watchdog::new(MAX_CONSECUTIVE_ERRORS, settings.max_resets, RESET_BACKOFF);
         while *ice_1.borrow() == RTCIceConnectionState::Connected {
             // TODO: *Average* frame interval is correct but the min/max is off by a lot
             tokio::select! {
                 _ = interval.tick() => {
-                    if let Err(e) = input.encode() {
-                        log::error!("Error encoding: {e}");
+                    let result = input.encode();
+                    // Ends the session since the stream stays frozen otherwise
+                    if !recover(result, &mut watchdog, &mut input).await {
+                        break;
                     }
+                    input.publish_status();
+                }
+                Some(command) = input.command_rx.recv() => {
+                    input.handle_command(command);
                 }
                 msg = input.rtcp_rx.recv() => {
                     match msg {
@@ -284,6 +987,10 @@ pub async fn start_encoder(
                                 input.input.force_idr_on_next();
                                 log::info!("FIR received");
                             }
+                            RtcpEvent::Remb(bitrate) => {
+                                input.remb_bitrate = Some(bitrate);
+                                input.update_bitrate();
+                            }
                         }
                         None => break,
                     }
@@ -291,27 +998,309 @@ pub async fn start_encoder(
                 _ = input.bandwidth_estimate.changed() => {
                     input.update_bitrate();
                 }
+                _ = shutdown_1.requested() => break,
             }
         }
-        log::info!("Input thread exited");
+        log::info!("Input thread exited, {:?}", input.stats());
+
+        // Tearing down the encode session stops the output thread from waiting for a frame that
+        // will never come
+        stream_ended_1.store(true, Ordering::Release);
+        drop(input);
+        // Keeps the shutdown listener alive until the encoder is no longer used
+        if !matches!(
+            tokio::task::spawn_blocking(move || output_thread.join()).await,
+            Ok(Ok(()))
+        ) {
+            log::error!("Output thread panicked");
+        }
     }));
+}
 
-    let handle = tokio::runtime::Handle::current();
-    std::thread::spawn(move || {
-        while *ice_2.borrow() == RTCIceConnectionState::Connected {
-            if let Err(e) = output.write_packets(&handle) {
-                log::error!("Error while waiting for output: {e}");
-                break;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::util::Marshal;
+    use windows::Win32::Graphics::Dxgi::Common::{
+        DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_R10G10B10A2_UNORM, DXGI_FORMAT_R16G16B16A16_FLOAT,
+    };
+
+    #[test]
+    fn rtcp_event_parsing() {
+        let ssrc = 0x1234;
+        let pli = PictureLossIndication {
+            sender_ssrc: 1,
+            media_ssrc: ssrc,
+        };
+        let other_pli = PictureLossIndication {
+            sender_ssrc: 1,
+            media_ssrc: ssrc + 1,
+        };
+
+        let raw = pli.marshal().unwrap();
+        assert_eq!(parse_rtcp_events(&raw, ssrc, true), [RtcpEvent::Pli]);
+        let raw = other_pli.marshal().unwrap();
+        assert!(parse_rtcp_events(&raw, ssrc, true).is_empty());
+    }
+
+    #[test]
+    fn remb_parsing() {
+        let ssrc = 0x1234;
+        let remb = ReceiverEstimatedMaximumBitrate {
+            sender_ssrc: 1,
+            bitrate: 1_000_000.0,
+            ssrcs: vec![ssrc],
+        };
+        let raw = remb.marshal().unwrap();
+
+        assert_eq!(
+            parse_rtcp_events(&raw, ssrc, true),
+            [RtcpEvent::Remb(1_000_000)]
+        );
+        assert!(parse_rtcp_events(&raw, ssrc, false).is_empty());
+        assert!(parse_rtcp_events(&raw, ssrc + 1, true).is_empty());
+    }
+
+    #[test]
+    fn settings_clone() {
+        let settings = EncoderSettings::default();
+        let mut other = settings.clone();
+        assert_eq!(settings, other);
+
+        other.target_fps = 30;
+        assert_ne!(settings, other);
+    }
+
+    #[test]
+    fn bitrate_bounds() {
+        let settings = EncoderSettings {
+            min_bitrate: 1_000_000,
+            max_bitrate: 50_000_000,
+            ..Default::default()
+        };
+        let clamp = |bitrate| clamp_bitrate(bitrate, settings.min_bitrate, settings.max_bitrate);
+
+        assert_eq!(clamp(20_000_000), 20_000_000);
+        assert_eq!(clamp(100_000), 1_000_000);
+        assert_eq!(clamp(80_000_000), 50_000_000);
+        assert_eq!(clamp_bitrate(100_000, 1_000_000, 500_000), 500_000);
+    }
+
+    #[test]
+    fn rate_control_params() {
+        let fps = (60, 1);
+        assert_eq!(
+            RateControl::new(6_000_000, 1_000_000, 50_000_000, None, fps),
+            RateControl {
+                average_bitrate: 6_000_000,
+                vbv_buffer_size: 100_000,
+            }
+        );
+        assert_eq!(
+            RateControl::new(80_000_000, 1_000_000, 50_000_000, Some(400_000), fps),
+            RateControl {
+                average_bitrate: 50_000_000,
+                vbv_buffer_size: 400_000,
+            }
+        );
+        assert_eq!(
+            RateControl::new(100_000, 1_000_000, 500_000, None, fps),
+            RateControl {
+                average_bitrate: 500_000,
+                vbv_buffer_size: 8_333,
+            }
+        );
+    }
+
+    #[test]
+    fn bitrate_allocation() {
+        assert_eq!(video_bitrate(5_000_000, 128_000, 64_000), 4_872_000);
+        assert_eq!(video_bitrate(5_000_000, 0, 64_000), 5_000_000);
+        // Below the audio reservation
+        assert_eq!(video_bitrate(100_000, 128_000, 64_000), 64_000);
+        assert_eq!(video_bitrate(150_000, 128_000, 64_000), 64_000);
+    }
+
+    #[test]
+    fn vbv_size() {
+        assert_eq!(single_frame_vbv_size(6_000_000, 60, 1), 100_000);
+        assert_eq!(single_frame_vbv_size(6_000_000, 60000, 1001), 100_100);
+        assert_eq!(single_frame_vbv_size(MAX_BITRATE_BPS, 60, 1), 1_666_666);
+        assert_eq!(single_frame_vbv_size(6_000_000, 0, 0), 0);
+    }
+
+    #[test]
+    fn frame_size_validation() {
+        let bgra = DXGI_FORMAT_B8G8R8A8_UNORM;
+        let desc = |width, height| D3D11_TEXTURE2D_DESC {
+            Width: width,
+            Height: height,
+            Format: bgra,
+            ..Default::default()
+        };
+
+        assert!(check_frame_desc((1920, 1080), bgra, &desc(1920, 1080)).is_ok());
+        assert!(matches!(
+            check_frame_desc((1920, 1080), bgra, &desc(2560, 1440)),
+            Err(EncodeError::FrameSizeMismatch {
+                expected: (1920, 1080),
+                actual: (2560, 1440),
+            })
+        ));
+        assert!(check_frame_desc((0, 0), bgra, &desc(0, 0)).is_err());
+    }
+
+    #[test]
+    fn frame_format_validation() {
+        let desc = |format| D3D11_TEXTURE2D_DESC {
+            Width: 1920,
+            Height: 1080,
+            Format: format,
+            ..Default::default()
+        };
+        let size = (1920, 1080);
+
+        assert!(check_frame_desc(
+            size,
+            DXGI_FORMAT_R10G10B10A2_UNORM,
+            &desc(DXGI_FORMAT_R10G10B10A2_UNORM)
+        )
+        .is_ok());
+        assert!(matches!(
+            check_frame_desc(
+                size,
+                DXGI_FORMAT_B8G8R8A8_UNORM,
+                &desc(DXGI_FORMAT_R10G10B10A2_UNORM)
+            ),
+            Err(EncodeError::FrameFormatMismatch {
+                expected: DXGI_FORMAT_B8G8R8A8_UNORM,
+                actual: DXGI_FORMAT_R10G10B10A2_UNORM,
+            })
+        ));
+        assert!(matches!(
+            check_frame_desc(
+                size,
+                DXGI_FORMAT_B8G8R8A8_UNORM,
+                &desc(DXGI_FORMAT_R16G16B16A16_FLOAT)
+            ),
+            Err(EncodeError::UnsupportedFrameFormat(
+                DXGI_FORMAT_R16G16B16A16_FLOAT
+            ))
+        ));
+    }
+
+    #[derive(Default)]
+    struct MockEncoder {
+        resets: u32,
+        reconfigures: u32,
+        fail_reconfigure: bool,
+    }
+
+    impl Recover for MockEncoder {
+        fn reset(&mut self) {
+            self.resets += 1;
+        }
+
+        fn reconfigure(&mut self) -> Result<(), EncodeError> {
+            self.reconfigures += 1;
+            if self.fail_reconfigure {
+                capture_error()
+            } else {
+                Ok(())
             }
         }
-        log::info!("Output thread exited");
-    });
-}
+    }
+
+    fn capture_error() -> Result<(), EncodeError> {
+        Err(EncodeError::Capture(AcquireFrameError::Unknown))
+    }
+
+    #[tokio::test]
+    async fn recovery_resets_then_gives_up() {
+        let mut watchdog = Watchdog::new(2, 2, Duration::ZERO);
+        let mut encoder = MockEncoder::default();
+
+        assert!(recover(capture_error(), &mut watchdog, &mut encoder).await);
+        assert_eq!(encoder.resets, 0);
+        assert!(recover(capture_error(), &mut watchdog, &mut encoder).await);
+        assert_eq!(encoder.resets, 1);
+
+        // A working frame after the reset means the reset helped
+        assert!(recover(Ok(()), &mut watchdog, &mut encoder).await);
+        for _ in 0..4 {
+            assert!(recover(capture_error(), &mut watchdog, &mut encoder).await);
+        }
+        assert_eq!(encoder.resets, 3);
+
+        assert!(recover(capture_error(), &mut watchdog, &mut encoder).await);
+        assert!(!recover(capture_error(), &mut watchdog, &mut encoder).await);
+        assert_eq!(encoder.resets, 3);
+    }
+
+    #[tokio::test]
+    async fn resolution_change_reconfigures() {
+        let mismatch = |actual| {
+            Err(EncodeError::FrameSizeMismatch {
+                expected: (1920, 1080),
+                actual,
+            })
+        };
+        let mut watchdog = Watchdog::new(2, 1, Duration::ZERO);
+        let mut encoder = MockEncoder::default();
+
+        for _ in 0..5 {
+            assert!(recover(mismatch((2560, 1440)), &mut watchdog, &mut encoder).await);
+        }
+        assert_eq!(encoder.reconfigures, 5);
+        assert_eq!(encoder.resets, 0);
+
+        // An empty frame is still a fault
+        assert!(recover(mismatch((0, 0)), &mut watchdog, &mut encoder).await);
+        assert!(recover(mismatch((0, 0)), &mut watchdog, &mut encoder).await);
+        assert_eq!(encoder.reconfigures, 5);
+        assert_eq!(encoder.resets, 1);
+
+        // A new format is handled like a new size
+        let format_change = Err(EncodeError::FrameFormatMismatch {
+            expected: DXGI_FORMAT_B8G8R8A8_UNORM,
+            actual: DXGI_FORMAT_R10G10B10A2_UNORM,
+        });
+        assert!(recover(format_change, &mut watchdog, &mut encoder).await);
+        assert_eq!(encoder.reconfigures, 6);
+        assert_eq!(encoder.resets, 1);
+
+        // So is failing to rebuild the encoder
+        encoder.fail_reconfigure = true;
+        assert!(recover(mismatch((2560, 1440)), &mut watchdog, &mut encoder).await);
+        assert!(!recover(mismatch((2560, 1440)), &mut watchdog, &mut encoder).await);
+    }
+
+    #[test]
+    fn unchanged_frame_detection() {
+        let rect = RECT {
+            left: 0,
+            top: 0,
+            right: 16,
+            bottom: 16,
+        };
+
+        assert!(has_new_image(Some(&[rect])));
+        assert!(!has_new_image(Some(&[])));
+        assert!(has_new_image(None));
+    }
+
+    #[test]
+    fn skipped_frame_encoded_when_idle() {
+        // Without idle repeats only a skipped frame is encoded
+        assert!(idle_frame_due(true, None));
+        assert!(!idle_frame_due(false, None));
 
-fn timer_frequency() -> u64 {
-    let mut timer_frequency = 0;
-    unsafe {
-        QueryPerformanceFrequency(&mut timer_frequency);
+        // The skipped frame restarts the idle repeat interval
+        let frame_interval = Duration::from_millis(16);
+        let mut idle_repeat = IdleRepeat::new(frame_interval * 2, frame_interval);
+        assert!(!idle_frame_due(false, Some(&mut idle_repeat)));
+        assert!(idle_frame_due(true, Some(&mut idle_repeat)));
+        assert!(!idle_frame_due(false, Some(&mut idle_repeat)));
+        assert!(idle_frame_due(false, Some(&mut idle_repeat)));
     }
-    timer_frequency as u64
 }
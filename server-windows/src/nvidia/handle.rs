@@ -0,0 +1,151 @@
+use super::{pause::PauseHandle, stats::FrameStats};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    watch,
+};
+
+/// Commands that can be sent to a running encoder.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum EncoderCommand {
+    /// Encode the next frame as an IDR frame.
+    ForceIdr,
+    /// Use a fixed bitrate in bits per second instead of following the bandwidth estimate. `None`
+    /// goes back to the estimate.
+    SetBitrate(Option<u32>),
+}
+
+/// State of a running encoder.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct EncoderStatus {
+    /// Average bitrate in bits per second.
+    pub bitrate: u32,
+    pub resolution: (u32, u32),
+    pub target_fps: u32,
+    pub stats: FrameStats,
+}
+
+/// Controls an encoder from outside of its threads.
+#[derive(Debug, Clone)]
+pub struct EncoderHandle {
+    command_tx: UnboundedSender<EncoderCommand>,
+    status_rx: watch::Receiver<EncoderStatus>,
+    pause_handle: PauseHandle,
+}
+
+/// The encoder's side of an `EncoderHandle`.
+pub struct EncoderControl {
+    pub command_rx: UnboundedReceiver<EncoderCommand>,
+    pub status_tx: watch::Sender<EncoderStatus>,
+    pub pause_handle: PauseHandle,
+}
+
+/// Creates an `EncoderHandle` and the `EncoderControl` to be passed to the encoder.
+pub fn encoder_handle() -> (EncoderHandle, EncoderControl) {
+    let (command_tx, command_rx) = unbounded_channel();
+    let (status_tx, status_rx) = watch::channel(EncoderStatus::default());
+    let pause_handle = PauseHandle::default();
+
+    let handle = EncoderHandle {
+        command_tx,
+        status_rx,
+        pause_handle: pause_handle.clone(),
+    };
+    let control = EncoderControl {
+        command_rx,
+        status_tx,
+        pause_handle,
+    };
+    (handle, control)
+}
+
+impl EncoderHandle {
+    /// Requests an IDR frame.
+    pub fn force_idr(&self) {
+        self.send(EncoderCommand::ForceIdr);
+    }
+
+    /// Sets a fixed bitrate in bits per second. `None` makes the bitrate follow the bandwidth
+    /// estimate again.
+    pub fn set_bitrate(&self, bitrate: Option<u32>) {
+        self.send(EncoderCommand::SetBitrate(bitrate));
+    }
+
+    /// Stops capturing and encoding without closing the connection.
+    pub fn pause(&self) {
+        self.pause_handle.pause();
+    }
+
+    /// Starts capturing again after `pause`.
+    pub fn resume(&self) {
+        self.pause_handle.resume();
+    }
+
+    /// Returns the latest state published by the encoder.
+    pub fn status(&self) -> EncoderStatus {
+        *self.status_rx.borrow()
+    }
+
+    /// Waits until the encoder has stopped, whether it was never built, gave up after errors or
+    /// was shut down.
+    pub async fn closed(&self) {
+        let mut status_rx = self.status_rx.clone();
+        while status_rx.changed().await.is_ok() {}
+    }
+
+    fn send(&self, command: EncoderCommand) {
+        if self.command_tx.send(command).is_err() {
+            log::warn!("Encoder has already exited");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handle_commands() {
+        let (handle, mut control) = encoder_handle();
+
+        handle.force_idr();
+        handle.set_bitrate(Some(1_000_000));
+        assert_eq!(control.command_rx.try_recv(), Ok(EncoderCommand::ForceIdr));
+        assert_eq!(
+            control.command_rx.try_recv(),
+            Ok(EncoderCommand::SetBitrate(Some(1_000_000)))
+        );
+
+        handle.pause();
+        assert!(control.pause_handle.is_paused());
+
+        let status = EncoderStatus {
+            bitrate: 1_000_000,
+            resolution: (1920, 1080),
+            target_fps: 60,
+            stats: FrameStats::default(),
+        };
+        control.status_tx.send_replace(status);
+        assert_eq!(handle.status(), status);
+    }
+
+    #[tokio::test]
+    async fn handle_closed() {
+        use std::time::Duration;
+
+        let (handle, control) = encoder_handle();
+        let closed = handle.closed();
+        tokio::pin!(closed);
+
+        control.status_tx.send_replace(EncoderStatus::default());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), closed.as_mut())
+                .await
+                .is_err()
+        );
+
+        drop(control);
+        assert!(tokio::time::timeout(Duration::from_secs(1), closed)
+            .await
+            .is_ok());
+    }
+}
@@ -1,4 +1,13 @@
 mod builder;
+mod clock;
 mod encoder;
+mod handle;
+mod nal;
+mod pacing;
+mod pause;
+mod queue;
+mod stats;
+mod watchdog;
 
-pub use builder::NvidiaEncoderBuilder;
+pub use builder::{is_encoder_available, NvidiaEncoderBuilder, StreamQuality};
+pub use handle::EncoderHandle;
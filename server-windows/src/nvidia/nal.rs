@@ -0,0 +1,86 @@
+/// Splits an Annex B byte stream into NAL units, excluding the start codes.
+///
+/// Both three and four byte start codes are handled. Emulation prevention bytes are left as is
+/// since `00 00 03` can never be mistaken for a start code.
+pub fn nal_units(stream: &[u8]) -> NalUnits<'_> {
+    NalUnits { stream }
+}
+
+/// Iterator returned by `nal_units`.
+pub struct NalUnits<'a> {
+    stream: &'a [u8],
+}
+
+impl<'a> Iterator for NalUnits<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let start = find_start_code(self.stream)? + 3;
+            let rest = &self.stream[start..];
+            let end = find_start_code(rest).unwrap_or(rest.len());
+            self.stream = &rest[end..];
+
+            // A NAL unit never ends in a zero byte so these are either the first byte of a
+            // four byte start code or `trailing_zero_8bits`
+            let mut nalu = &rest[..end];
+            while let [head @ .., 0] = nalu {
+                nalu = head;
+            }
+
+            if !nalu.is_empty() {
+                return Some(nalu);
+            }
+        }
+    }
+}
+
+fn find_start_code(data: &[u8]) -> Option<usize> {
+    data.windows(3).position(|window| window == [0, 0, 1])
+}
+
+/// Checks if an Annex B H.264 bitstream contains an IDR slice.
+pub fn is_keyframe(bitstream: &[u8]) -> bool {
+    const NALU_TYPE_IDR: u8 = 5;
+
+    nal_units(bitstream).any(|nalu| nalu[0] & 0x1f == NALU_TYPE_IDR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_mixed_start_codes() {
+        // SPS with a four byte start code, PPS with a three byte start code, then an IDR slice
+        // containing an emulation prevention byte followed by trailing zeros
+        let stream = [
+            0, 0, 0, 1, 0x67, 0x42, 0x00, 0x1f, 0, 0, 1, 0x68, 0xce, 0x3c, 0x80, 0, 0, 0, 1, 0x65,
+            0x88, 0x00, 0x00, 0x03, 0x01, 0x84, 0, 0,
+        ];
+        let nalus: Vec<&[u8]> = nal_units(&stream).collect();
+
+        assert_eq!(
+            nalus,
+            [
+                &[0x67, 0x42, 0x00, 0x1f][..],
+                &[0x68, 0xce, 0x3c, 0x80][..],
+                &[0x65, 0x88, 0x00, 0x00, 0x03, 0x01, 0x84][..],
+            ]
+        );
+        assert_eq!(nal_units(&[]).count(), 0);
+        assert_eq!(nal_units(&[0, 0, 1]).count(), 0);
+    }
+
+    #[test]
+    fn keyframe_detection() {
+        let sps_pps_idr = [
+            0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xce, 0, 0, 1, 0x65, 0x88,
+        ];
+        let non_idr = [0, 0, 0, 1, 0x41, 0x9a, 0, 0, 1, 0x41, 0x01];
+
+        assert!(is_keyframe(&sps_pps_idr));
+        assert!(!is_keyframe(&non_idr));
+        assert!(!is_keyframe(&[]));
+    }
+}
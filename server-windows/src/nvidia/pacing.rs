@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+/// Decides which of the captured frames get encoded so that the output does not exceed the target
+/// framerate, regardless of the refresh rate of the display.
+pub struct FramePacer {
+    /// Ideal time between frames in units of the timer frequency.
+    frame_interval: u64,
+    /// Frames earlier than this many ticks before the deadline are dropped.
+    tolerance: u64,
+    /// Ideal timestamp of the next frame.
+    next_deadline: Option<u64>,
+}
+
+impl FramePacer {
+    /// Creates a new `FramePacer` for timestamps measured with a timer of `timer_frequency` Hz.
+    pub fn new(target_fps: u32, timer_frequency: u64) -> FramePacer {
+        assert!(target_fps > 0, "Target framerate must be non-zero");
+        let frame_interval = timer_frequency / target_fps as u64;
+        FramePacer {
+            frame_interval,
+            // Absorbs the jitter of a display with the same refresh rate as the target
+            tolerance: frame_interval / 8,
+            next_deadline: None,
+        }
+    }
+
+    /// Returns true if the frame presented at `timestamp` should be encoded.
+    pub fn should_encode(&mut self, timestamp: u64) -> bool {
+        match self.next_deadline {
+            Some(deadline) if timestamp + self.tolerance < deadline => false,
+            Some(deadline) if timestamp < deadline + self.frame_interval => {
+                // Advance from the previous deadline instead of the timestamp so that the average
+                // framerate matches the target when the display is faster
+                self.next_deadline = Some(deadline + self.frame_interval);
+                true
+            }
+            _ => {
+                // First frame or the display was idle
+                self.next_deadline = Some(timestamp + self.frame_interval);
+                true
+            }
+        }
+    }
+}
+
+/// Counts the duplication timeouts to decide when the last frame should be encoded again.
+pub struct IdleRepeat {
+    timeouts_per_repeat: u32,
+    timeouts: u32,
+}
+
+impl IdleRepeat {
+    /// Creates a new `IdleRepeat` that repeats a frame every `repeat_interval` while the
+    /// duplicator times out every `timeout`.
+    pub fn new(repeat_interval: Duration, timeout: Duration) -> IdleRepeat {
+        let timeout = timeout.as_nanos().max(1);
+        let timeouts_per_repeat = (repeat_interval.as_nanos() + timeout - 1) / timeout;
+        IdleRepeat {
+            timeouts_per_repeat: timeouts_per_repeat.clamp(1, u32::MAX as u128) as u32,
+            timeouts: 0,
+        }
+    }
+
+    /// Called on a duplication timeout. Returns true if the last frame should be repeated.
+    pub fn on_timeout(&mut self) -> bool {
+        self.timeouts += 1;
+        if self.timeouts >= self.timeouts_per_repeat {
+            self.timeouts = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Called when a new frame was acquired.
+    pub fn reset(&mut self) {
+        self.timeouts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMER_FREQUENCY: u64 = 10_000_000;
+
+    /// Counts the frames encoded out of `num_frames` presented at `display_fps`.
+    fn encoded_frames(target_fps: u32, display_fps: u64, num_frames: u64) -> usize {
+        let mut pacer = FramePacer::new(target_fps, TIMER_FREQUENCY);
+        (0..num_frames)
+            .map(|i| i * TIMER_FREQUENCY / display_fps)
+            .filter(|&timestamp| pacer.should_encode(timestamp))
+            .count()
+    }
+
+    #[test]
+    fn pacing_faster_display() {
+        // One second of 144 Hz and 120 Hz
+        assert_eq!(encoded_frames(60, 144, 144), 60);
+        assert_eq!(encoded_frames(60, 120, 120), 60);
+    }
+
+    #[test]
+    fn pacing_same_rate_display() {
+        let mut pacer = FramePacer::new(60, TIMER_FREQUENCY);
+        let interval = TIMER_FREQUENCY / 60;
+
+        assert!(pacer.should_encode(0));
+        // Slightly early frame is still encoded
+        assert!(pacer.should_encode(interval - interval / 16));
+        assert!(pacer.should_encode(2 * interval + interval / 16));
+        // Too early
+        assert!(!pacer.should_encode(2 * interval + interval / 2));
+    }
+
+    #[test]
+    fn pacing_after_idle() {
+        let mut pacer = FramePacer::new(30, TIMER_FREQUENCY);
+        let interval = TIMER_FREQUENCY / 30;
+
+        assert!(pacer.should_encode(0));
+        // Nothing changed on screen for a while
+        assert!(pacer.should_encode(100 * interval));
+        assert!(!pacer.should_encode(100 * interval + interval / 2));
+        assert!(pacer.should_encode(101 * interval));
+    }
+
+    #[test]
+    fn idle_repeat_after_timeouts() {
+        let mut idle_repeat =
+            IdleRepeat::new(Duration::from_millis(100), Duration::from_millis(16));
+
+        // 100 ms worth of 16 ms timeouts
+        for _ in 0..6 {
+            assert!(!idle_repeat.on_timeout());
+        }
+        assert!(idle_repeat.on_timeout());
+        assert!(!idle_repeat.on_timeout());
+
+        // A new frame restarts the count
+        idle_repeat.reset();
+        for _ in 0..6 {
+            assert!(!idle_repeat.on_timeout());
+        }
+        assert!(idle_repeat.on_timeout());
+    }
+
+    #[test]
+    fn idle_repeat_shorter_than_timeout() {
+        let mut idle_repeat = IdleRepeat::new(Duration::from_millis(1), Duration::from_millis(16));
+        assert!(idle_repeat.on_timeout());
+        assert!(idle_repeat.on_timeout());
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Pauses and resumes the capture and encoding of a stream while keeping the connection alive.
+#[derive(Debug, Default, Clone)]
+pub struct PauseHandle {
+    paused: Arc<AtomicBool>,
+}
+
+impl PauseHandle {
+    /// Stops capturing and encoding. Nothing is sent until `resume` is called.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Release);
+    }
+
+    /// Starts capturing again. The first frame after resuming is a keyframe.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+}
+
+/// State of the stream as seen by the input loop.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PauseState {
+    Running,
+    /// First poll after being paused.
+    Pausing,
+    Paused,
+    /// First poll after being resumed.
+    Resumed,
+}
+
+/// Tracks the transitions of a `PauseHandle` from the input loop.
+pub struct PauseTracker {
+    handle: PauseHandle,
+    was_paused: bool,
+}
+
+impl PauseTracker {
+    pub fn new(handle: PauseHandle) -> PauseTracker {
+        PauseTracker {
+            handle,
+            was_paused: false,
+        }
+    }
+
+    /// Called before encoding a frame.
+    pub fn poll(&mut self) -> PauseState {
+        let is_paused = self.handle.is_paused();
+        let was_paused = std::mem::replace(&mut self.was_paused, is_paused);
+        match (was_paused, is_paused) {
+            (false, true) => PauseState::Pausing,
+            (true, true) => PauseState::Paused,
+            (true, false) => PauseState::Resumed,
+            (false, false) => PauseState::Running,
+        }
+    }
+}
+
+/// Resources of a stream that are released while it is paused.
+pub trait Suspend {
+    /// Called once when the stream gets paused.
+    fn suspend(&mut self);
+    /// Called once when the stream is resumed.
+    fn resume(&mut self);
+}
+
+/// Suspends or resumes `stream` on the transitions of `state`. Returns false if the stream is
+/// paused and nothing should be encoded.
+pub fn apply_pause_state(state: PauseState, stream: &mut impl Suspend) -> bool {
+    match state {
+        PauseState::Running => true,
+        PauseState::Pausing => {
+            stream.suspend();
+            false
+        }
+        PauseState::Paused => false,
+        PauseState::Resumed => {
+            stream.resume();
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pause_and_resume() {
+        let handle = PauseHandle::default();
+        let mut tracker = PauseTracker::new(handle.clone());
+        assert_eq!(tracker.poll(), PauseState::Running);
+
+        handle.pause();
+        assert_eq!(tracker.poll(), PauseState::Pausing);
+        assert_eq!(tracker.poll(), PauseState::Paused);
+
+        handle.resume();
+        assert_eq!(tracker.poll(), PauseState::Resumed);
+        assert_eq!(tracker.poll(), PauseState::Running);
+    }
+
+    #[derive(Default)]
+    struct MockStream {
+        suspended: bool,
+        suspends: u32,
+        resumes: u32,
+    }
+
+    impl Suspend for MockStream {
+        fn suspend(&mut self) {
+            assert!(!self.suspended);
+            self.suspended = true;
+            self.suspends += 1;
+        }
+
+        fn resume(&mut self) {
+            assert!(self.suspended);
+            self.suspended = false;
+            self.resumes += 1;
+        }
+    }
+
+    #[test]
+    fn suspend_while_paused() {
+        let handle = PauseHandle::default();
+        let mut tracker = PauseTracker::new(handle.clone());
+        let mut stream = MockStream::default();
+        let mut poll = |stream: &mut MockStream| apply_pause_state(tracker.poll(), stream);
+
+        assert!(poll(&mut stream));
+        handle.pause();
+        for _ in 0..3 {
+            assert!(!poll(&mut stream));
+            assert!(stream.suspended);
+        }
+        assert_eq!(stream.suspends, 1);
+
+        handle.resume();
+        assert!(poll(&mut stream));
+        assert!(poll(&mut stream));
+        assert!(!stream.suspended);
+        assert_eq!((stream.suspends, stream.resumes), (1, 1));
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use tokio::sync::Notify;
+
+/// An encoded frame waiting to be packetized and sent.
+#[derive(Debug)]
+pub struct EncodedFrame {
+    pub data: Vec<u8>,
+    pub timestamp: u32,
+    pub is_keyframe: bool,
+}
+
+/// Bounded queue between the NVENC output thread and the RTP writer.
+///
+/// When the writer can't keep up, the oldest frames are dropped instead of letting the queue (and
+/// the latency) grow. Since the inter frames following a dropped frame can't be decoded, the queue
+/// then discards everything up to the next keyframe and asks the encoder for one.
+pub struct FrameQueue {
+    state: Mutex<FrameQueueState>,
+    notify: Notify,
+    capacity: usize,
+    keyframe_requested: AtomicBool,
+    closed: AtomicBool,
+    dropped: AtomicU64,
+}
+
+struct FrameQueueState {
+    frames: VecDeque<EncodedFrame>,
+    waiting_for_keyframe: bool,
+}
+
+impl FrameQueue {
+    /// Creates a new `FrameQueue` that holds at most `capacity` frames.
+    pub fn new(capacity: usize) -> FrameQueue {
+        assert!(capacity > 0, "`FrameQueue` capacity must be non-zero");
+        FrameQueue {
+            state: Mutex::new(FrameQueueState {
+                frames: VecDeque::with_capacity(capacity),
+                waiting_for_keyframe: false,
+            }),
+            notify: Notify::new(),
+            capacity,
+            keyframe_requested: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue a frame for sending, dropping old frames if the queue is full.
+    pub fn push(&self, frame: EncodedFrame) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.waiting_for_keyframe {
+            if !frame.is_keyframe {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            state.waiting_for_keyframe = false;
+        }
+
+        if state.frames.len() == self.capacity {
+            // Drop the oldest frame along with the inter frames that depend on it
+            state.frames.pop_front();
+            let mut dropped = 1;
+            while let Some(front) = state.frames.front() {
+                if front.is_keyframe {
+                    break;
+                }
+                state.frames.pop_front();
+                dropped += 1;
+            }
+            self.dropped.fetch_add(dropped, Ordering::Relaxed);
+
+            if state.frames.is_empty() && !frame.is_keyframe {
+                state.waiting_for_keyframe = true;
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                self.keyframe_requested.store(true, Ordering::Release);
+                log::warn!("RTP writer is congested, waiting for the next keyframe");
+                return;
+            }
+            log::debug!("Dropped frames, queue depth: {}", state.frames.len());
+        }
+
+        state.frames.push_back(frame);
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    /// Wait for the next frame. Returns `None` once the queue is closed and drained.
+    pub async fn pop(&self) -> Option<EncodedFrame> {
+        loop {
+            let notified = self.notify.notified();
+            let frame = self.state.lock().unwrap().frames.pop_front();
+            if let Some(frame) = frame {
+                return Some(frame);
+            }
+            if self.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            notified.await;
+        }
+    }
+
+    /// Number of frames waiting to be sent.
+    pub fn depth(&self) -> usize {
+        self.state.lock().unwrap().frames.len()
+    }
+
+    /// Total number of frames dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if frames were dropped and a keyframe is needed to resume the stream.
+    /// The request is cleared after being read.
+    pub fn take_keyframe_request(&self) -> bool {
+        self.keyframe_requested.swap(false, Ordering::AcqRel)
+    }
+
+    /// Wake up the reader and signal that no more frames will be pushed.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.notify.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(timestamp: u32, is_keyframe: bool) -> EncodedFrame {
+        EncodedFrame {
+            data: Vec::new(),
+            timestamp,
+            is_keyframe,
+        }
+    }
+
+    fn timestamps(queue: &FrameQueue) -> Vec<u32> {
+        let state = queue.state.lock().unwrap();
+        state.frames.iter().map(|f| f.timestamp).collect()
+    }
+
+    #[test]
+    fn slow_writer_drops_oldest_frames() {
+        let queue = FrameQueue::new(4);
+        queue.push(frame(0, true));
+        queue.push(frame(1, false));
+        queue.push(frame(2, false));
+        queue.push(frame(3, true));
+        queue.push(frame(4, false));
+
+        // Frames 0 to 2 are dropped together since 1 and 2 depend on 0
+        assert_eq!(timestamps(&queue), [3, 4]);
+        assert_eq!(queue.dropped(), 3);
+        assert!(!queue.take_keyframe_request());
+    }
+
+    #[test]
+    fn slow_writer_waits_for_keyframe() {
+        let queue = FrameQueue::new(2);
+        queue.push(frame(0, true));
+        queue.push(frame(1, false));
+        queue.push(frame(2, false));
+
+        assert_eq!(queue.depth(), 0);
+        assert!(queue.take_keyframe_request());
+        assert!(!queue.take_keyframe_request());
+
+        queue.push(frame(3, false));
+        assert_eq!(queue.depth(), 0);
+        assert_eq!(queue.dropped(), 4);
+
+        queue.push(frame(4, true));
+        queue.push(frame(5, false));
+        assert_eq!(timestamps(&queue), [4, 5]);
+    }
+
+    #[tokio::test]
+    async fn pop_returns_none_after_close() {
+        let queue = FrameQueue::new(2);
+        queue.push(frame(0, true));
+        queue.close();
+
+        assert_eq!(queue.pop().await.map(|f| f.timestamp), Some(0));
+        assert!(queue.pop().await.is_none());
+    }
+}
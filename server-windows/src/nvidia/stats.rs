@@ -0,0 +1,147 @@
+/// Number of frames that went through each stage of the capture pipeline.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct FrameStats {
+    /// Frames acquired from the duplicator, including those skipped by the pacing.
+    pub acquired: u64,
+    /// Frames sent to the encoder.
+    pub encoded: u64,
+    /// Encoded frames dropped because the RTP writer could not keep up.
+    pub dropped: u64,
+    /// What came out of the encoder.
+    pub output: OutputStats,
+}
+
+/// Size, type and QP of the frames written by the encoder.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct OutputStats {
+    /// Frames written by the encoder.
+    pub frames: u64,
+    /// Frames written as keyframes.
+    pub keyframes: u64,
+    /// Total size of the written frames in bytes.
+    pub bytes: u64,
+    /// Size in bytes of the last written frame.
+    pub last_size: u32,
+    /// Average QP of the last written frame.
+    pub last_qp: u32,
+    qp_sum: u64,
+}
+
+impl OutputStats {
+    /// Adds a frame of `size` bytes that was encoded with an average QP of `qp`.
+    pub fn record(&mut self, size: u32, is_keyframe: bool, qp: u32) {
+        self.frames += 1;
+        if is_keyframe {
+            self.keyframes += 1;
+        }
+        self.bytes += size as u64;
+        self.last_size = size;
+        self.last_qp = qp;
+        self.qp_sum += qp as u64;
+    }
+
+    /// Average size in bytes of the written frames. `None` until a frame is written.
+    pub fn average_size(&self) -> Option<u64> {
+        self.bytes.checked_div(self.frames)
+    }
+
+    /// Average QP of the written frames. `None` until a frame is written.
+    pub fn average_qp(&self) -> Option<u32> {
+        self.qp_sum.checked_div(self.frames).map(|qp| qp as u32)
+    }
+}
+
+/// Whether the framerate should change after an `OverrunDetector::update`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Overrun {
+    /// Not enough frames yet or the drop rate is acceptable.
+    None,
+    /// Too many frames were dropped during the window.
+    Detected,
+    /// No frames were dropped during the window.
+    Cleared,
+}
+
+/// Detects when the encoded frames are dropped faster than the acceptable rate.
+pub struct OverrunDetector {
+    /// Number of encoded frames per window.
+    window: u64,
+    /// Maximum percentage of the encoded frames that can be dropped in a window.
+    max_drop_percent: u64,
+    window_start: FrameStats,
+}
+
+impl OverrunDetector {
+    /// Creates a new `OverrunDetector` that checks the drop rate every `window` encoded frames.
+    pub fn new(window: u64, max_drop_percent: u64) -> OverrunDetector {
+        assert!(window > 0, "Window must be non-zero");
+        OverrunDetector {
+            window,
+            max_drop_percent,
+            window_start: FrameStats::default(),
+        }
+    }
+
+    /// Called with the latest counts. Starts a new window once enough frames have been encoded.
+    pub fn update(&mut self, stats: FrameStats) -> Overrun {
+        let encoded = stats.encoded - self.window_start.encoded;
+        if encoded < self.window {
+            return Overrun::None;
+        }
+        let dropped = stats.dropped - self.window_start.dropped;
+        self.window_start = stats;
+
+        if dropped * 100 > encoded * self.max_drop_percent {
+            Overrun::Detected
+        } else if dropped == 0 {
+            Overrun::Cleared
+        } else {
+            Overrun::None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(encoded: u64, dropped: u64) -> FrameStats {
+        FrameStats {
+            acquired: encoded,
+            encoded,
+            dropped,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn overrun_detection() {
+        let mut detector = OverrunDetector::new(60, 10);
+
+        assert_eq!(detector.update(stats(30, 20)), Overrun::None);
+        assert_eq!(detector.update(stats(60, 20)), Overrun::Detected);
+        // 6 out of 60 is at the limit
+        assert_eq!(detector.update(stats(120, 26)), Overrun::None);
+        assert_eq!(detector.update(stats(180, 26)), Overrun::Cleared);
+    }
+
+    #[test]
+    fn output_stats_aggregation() {
+        let mut stats = OutputStats::default();
+        assert_eq!(stats.average_size(), None);
+        assert_eq!(stats.average_qp(), None);
+
+        stats.record(40_000, true, 20);
+        stats.record(5_000, false, 26);
+        stats.record(6_000, false, 29);
+
+        assert_eq!(stats.frames, 3);
+        assert_eq!(stats.keyframes, 1);
+        assert_eq!(stats.bytes, 51_000);
+        assert_eq!(stats.last_size, 6_000);
+        assert_eq!(stats.last_qp, 29);
+        assert_eq!(stats.average_size(), Some(17_000));
+        // 75 / 3
+        assert_eq!(stats.average_qp(), Some(25));
+    }
+}
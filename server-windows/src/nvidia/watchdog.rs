@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+/// What the input loop should do after an encode error.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Recovery {
+    /// Keep going since the error may be transient.
+    Ignore,
+    /// Reset the capture after waiting for the given backoff.
+    Reset(Duration),
+    /// The resets did not help so the stream should be stopped.
+    GiveUp,
+}
+
+/// Counts the consecutive encode errors to decide when the capture needs to be reset, e.g. after
+/// a driver reset.
+pub struct Watchdog {
+    max_errors: u32,
+    max_resets: u32,
+    base_backoff: Duration,
+    errors: u32,
+    resets: u32,
+    total_resets: u32,
+}
+
+impl Watchdog {
+    /// Creates a new `Watchdog` that resets after `max_errors` consecutive errors, and gives up
+    /// after `max_resets` resets in a row failed to fix the errors.
+    pub fn new(max_errors: u32, max_resets: u32, base_backoff: Duration) -> Watchdog {
+        assert!(max_errors > 0, "`max_errors` must be non-zero");
+        Watchdog {
+            max_errors,
+            max_resets,
+            base_backoff,
+            errors: 0,
+            resets: 0,
+            total_resets: 0,
+        }
+    }
+
+    /// Called after a frame was handled without errors.
+    pub fn on_success(&mut self) {
+        self.errors = 0;
+        self.resets = 0;
+    }
+
+    /// Called after an encode error. The backoff doubles with each reset that did not help.
+    pub fn on_error(&mut self) -> Recovery {
+        self.errors += 1;
+        if self.errors < self.max_errors {
+            return Recovery::Ignore;
+        }
+        self.errors = 0;
+
+        if self.resets >= self.max_resets {
+            return Recovery::GiveUp;
+        }
+        let backoff = self.base_backoff.saturating_mul(1 << self.resets.min(16));
+        self.resets += 1;
+        self.total_resets += 1;
+        Recovery::Reset(backoff)
+    }
+
+    /// Number of resets since the stream started.
+    pub fn total_resets(&self) -> u32 {
+        self.total_resets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transient_error_resets() {
+        let backoff = Duration::from_millis(100);
+        let mut watchdog = Watchdog::new(2, 3, backoff);
+
+        assert_eq!(watchdog.on_error(), Recovery::Ignore);
+        assert_eq!(watchdog.on_error(), Recovery::Reset(backoff));
+        watchdog.on_success();
+
+        assert_eq!(watchdog.on_error(), Recovery::Ignore);
+        assert_eq!(watchdog.on_error(), Recovery::Reset(backoff));
+        assert_eq!(watchdog.total_resets(), 2);
+    }
+
+    #[test]
+    fn persistent_error_gives_up() {
+        let backoff = Duration::from_millis(100);
+        let mut watchdog = Watchdog::new(1, 3, backoff);
+
+        assert_eq!(watchdog.on_error(), Recovery::Reset(backoff));
+        assert_eq!(watchdog.on_error(), Recovery::Reset(backoff * 2));
+        assert_eq!(watchdog.on_error(), Recovery::Reset(backoff * 4));
+        assert_eq!(watchdog.on_error(), Recovery::GiveUp);
+        assert_eq!(watchdog.total_resets(), 3);
+    }
+}
@@ -1,7 +1,17 @@
-use crate::{input::controls_handler, nvidia::NvidiaEncoderBuilder, signaler::WebSocketSignaler};
+use crate::{
+    config::{BitrateRange, EncoderOptions, SessionLimits},
+    input::{controls_handler, InputSettings},
+    nvidia::{is_encoder_available, NvidiaEncoderBuilder, StreamQuality},
+    session::{activity_channel, session_timeout, SessionTimeout},
+    shutdown::{Shutdown, ShutdownListener},
+    signaler::WebSocketSignaler,
+};
+use serde::Serialize;
 use std::{
+    future::Future,
     net::SocketAddr,
     sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 use warp::{
     http::{Response, StatusCode},
@@ -16,7 +26,46 @@ const NOT_FOUND: &'static str = include_str!("html/not_found.html");
 
 static DUPLICATOR_RUNNING: AtomicBool = AtomicBool::new(false);
 
-pub async fn http_server(addr: impl Into<SocketAddr>) {
+const SESSION_GRACE_PERIOD: Duration = Duration::from_secs(2);
+/// Display that is captured and receives the input.
+const DISPLAY_INDEX: u32 = 0;
+
+/// Why a streaming session ended.
+#[derive(Debug, PartialEq, Eq)]
+enum SessionEnd {
+    PeerClosed,
+    EncoderStopped,
+    Shutdown,
+    Timeout(SessionTimeout),
+}
+
+/// Response of `GET /healthz`.
+#[derive(Debug, Serialize)]
+struct Health {
+    uptime_secs: u64,
+    active_sessions: u32,
+    encoder_available: bool,
+}
+
+/// Serves the client page and the signaling WebSocket until the server is asked to stop.
+pub async fn http_server(
+    addr: impl Into<SocketAddr>,
+    bitrate_range: BitrateRange,
+    session_limits: SessionLimits,
+    encoder_options: EncoderOptions,
+    max_input_rate: u32,
+    shutdown: ShutdownListener,
+) {
+    let start = Instant::now();
+    let encoder_available = is_encoder_available();
+    if !encoder_available {
+        log::error!("No usable NVENC encoder found");
+    }
+    let input_settings = InputSettings {
+        display_index: DISPLAY_INDEX,
+        max_event_rate: max_input_rate,
+    };
+
     // GET /
     let index = warp::path::end().map(|| {
         #[cfg(not(debug_assertions))]
@@ -45,14 +94,99 @@ pub async fn http_server(addr: impl Into<SocketAddr>) {
 
     let websocket = warp::path::end()
         .and(warp::ws())
-        .map(|ws: warp::ws::Ws| ws.on_upgrade(process_websocket));
+        .map(move |ws: warp::ws::Ws| {
+            let shutdown = shutdown.clone();
+            ws.on_upgrade(move |socket| {
+                process_websocket(
+                    socket,
+                    bitrate_range,
+                    session_limits,
+                    encoder_options,
+                    input_settings,
+                    shutdown,
+                )
+            })
+        });
+
+    let routes = websocket
+        .or(healthz(start, encoder_available))
+        .or(index)
+        .or(not_found);
+
+    let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(addr, shutdown_signal());
+    server.await;
+}
+
+/// Waits for Ctrl-C, the console window being closed, the user logging off or the system shutting
+/// down.
+async fn shutdown_signal() {
+    use tokio::signal::windows::{ctrl_close, ctrl_logoff, ctrl_shutdown};
+
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::error!("Unable to listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    let close = async {
+        match ctrl_close() {
+            Ok(mut close) => close.recv().await,
+            Err(e) => {
+                log::error!("Unable to listen for the console window closing: {e}");
+                std::future::pending().await
+            }
+        }
+    };
+    let logoff = async {
+        match ctrl_logoff() {
+            Ok(mut logoff) => logoff.recv().await,
+            Err(e) => {
+                log::error!("Unable to listen for logoff: {e}");
+                std::future::pending().await
+            }
+        }
+    };
+    let shutdown = async {
+        match ctrl_shutdown() {
+            Ok(mut shutdown) => shutdown.recv().await,
+            Err(e) => {
+                log::error!("Unable to listen for system shutdown: {e}");
+                std::future::pending().await
+            }
+        }
+    };
 
-    let routes = websocket.or(index).or(not_found);
+    tokio::select! {
+        _ = ctrl_c => log::info!("Ctrl-C pressed"),
+        _ = close => log::info!("Console window closed"),
+        _ = logoff => log::info!("User logged off"),
+        _ = shutdown => log::info!("System is shutting down"),
+    }
+}
 
-    warp::serve(routes).run(addr).await;
+// GET /healthz
+fn healthz(
+    start: Instant,
+    encoder_available: bool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("healthz").and(warp::get()).map(move || {
+        let health = Health {
+            uptime_secs: start.elapsed().as_secs(),
+            active_sessions: DUPLICATOR_RUNNING.load(Ordering::Acquire) as u32,
+            encoder_available,
+        };
+        warp::reply::json(&health)
+    })
 }
 
-async fn process_websocket(socket: WebSocket) {
+async fn process_websocket(
+    socket: WebSocket,
+    bitrate_range: BitrateRange,
+    session_limits: SessionLimits,
+    encoder_options: EncoderOptions,
+    input_settings: InputSettings,
+    mut shutdown: ShutdownListener,
+) {
     if DUPLICATOR_RUNNING.load(Ordering::Acquire) {
         return;
     }
@@ -64,16 +198,186 @@ async fn process_websocket(socket: WebSocket) {
     log::info!("WebSocket upgrade");
 
     tokio::spawn(async move {
+        // Stops the encoder threads when the session ends for any reason
+        let session_shutdown = Shutdown::new();
+        let (activity, activity_rx) = activity_channel();
+
+        let mut nvidia_encoder_builder = NvidiaEncoderBuilder::new(
+            "display-mirror".to_owned(),
+            "0".to_owned(),
+            session_shutdown.listener(),
+        );
+        nvidia_encoder_builder
+            .set_display_index(input_settings.display_index)
+            .set_min_bitrate(bitrate_range.min)
+            .set_max_bitrate(bitrate_range.max)
+            .set_start_bitrate(bitrate_range.start)
+            .set_audio_bitrate(bitrate_range.audio)
+            .set_target_fps(encoder_options.target_fps)
+            .set_idle_repeat_interval(encoder_options.idle_repeat_interval)
+            .set_vbv_buffer_size(encoder_options.vbv_buffer_size)
+            .set_max_resets(encoder_options.max_resets)
+            .set_remb_enabled(encoder_options.use_remb);
+        if encoder_options.lossless {
+            nvidia_encoder_builder.set_stream_quality(StreamQuality::Lossless);
+        }
+        let encoder_handle = nvidia_encoder_builder.handle();
+        let controls_encoder_handle = encoder_handle.clone();
+
         let mut encoder_builder = WebRtcBuilder::new(websocket_signaler, Role::Answerer);
         encoder_builder
-            .with_encoder(Box::new(NvidiaEncoderBuilder::new(
-                "display-mirror".to_owned(),
-                "0".to_owned(),
-            )))
-            .with_data_channel_handler(Box::new(controls_handler));
+            .with_encoder(Box::new(nvidia_encoder_builder))
+            .with_data_channel_handler(Box::new(move |data_channel| {
+                controls_handler(
+                    data_channel,
+                    input_settings,
+                    activity.clone(),
+                    controls_encoder_handle.clone(),
+                )
+            }));
         let encoder = encoder_builder.build().await.unwrap();
-        encoder.is_closed().await;
+        let end = wait_for_session_end(
+            encoder.is_closed(),
+            encoder_handle.closed(),
+            shutdown.requested(),
+            session_timeout(
+                activity_rx,
+                session_limits.idle_timeout,
+                session_limits.max_duration,
+            ),
+        )
+        .await;
+        log::info!("Closing session: {end:?}");
+        log::info!("Final encoder status: {:?}", encoder_handle.status());
+
+        teardown_session(encoder, session_shutdown, SESSION_GRACE_PERIOD).await;
         DUPLICATOR_RUNNING.store(false, Ordering::Release);
         log::info!("Exited");
     });
 }
+
+/// Waits for the first of the reasons to end a session.
+async fn wait_for_session_end(
+    peer_closed: impl Future<Output = ()>,
+    encoder_stopped: impl Future<Output = ()>,
+    shutdown: impl Future<Output = ()>,
+    timeout: impl Future<Output = SessionTimeout>,
+) -> SessionEnd {
+    tokio::select! {
+        _ = peer_closed => SessionEnd::PeerClosed,
+        _ = encoder_stopped => SessionEnd::EncoderStopped,
+        _ = shutdown => SessionEnd::Shutdown,
+        timeout = timeout => SessionEnd::Timeout(timeout),
+    }
+}
+
+/// Stops the encoder threads then drops the `peer`, which closes its connection if the client is
+/// still there.
+async fn teardown_session<P>(peer: P, session_shutdown: Shutdown, grace_period: Duration) {
+    if !session_shutdown.shutdown(grace_period).await {
+        log::warn!("Encoder threads did not exit within {grace_period:?}");
+    }
+    drop(peer);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        future::pending,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+    };
+
+    /// Stands in for the WebRTC peer and records the order of the teardown.
+    struct MockPeer {
+        events: Arc<AtomicU32>,
+        closed_at: Arc<AtomicU32>,
+    }
+
+    impl MockPeer {
+        async fn is_closed(&self) {
+            pending().await
+        }
+    }
+
+    impl Drop for MockPeer {
+        fn drop(&mut self) {
+            let event = self.events.fetch_add(1, Ordering::AcqRel) + 1;
+            self.closed_at.store(event, Ordering::Release);
+        }
+    }
+
+    #[tokio::test]
+    async fn healthz_response() {
+        let filter = healthz(Instant::now(), true);
+        let response = warp::test::request()
+            .method("GET")
+            .path("/healthz")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let health: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(health["uptime_secs"], 0);
+        assert_eq!(health["active_sessions"], 0);
+        assert_eq!(health["encoder_available"], true);
+        assert_eq!(health.as_object().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_ends_session() {
+        let (_activity, activity_rx) = activity_channel();
+        let timeout = session_timeout(activity_rx, Some(Duration::from_millis(50)), None);
+
+        let end = wait_for_session_end(pending(), pending(), pending(), timeout).await;
+        assert_eq!(end, SessionEnd::Timeout(SessionTimeout::Idle));
+    }
+
+    #[tokio::test]
+    async fn timeout_tears_down_session() {
+        let events = Arc::new(AtomicU32::new(0));
+        let closed_at = Arc::new(AtomicU32::new(0));
+        let encoder_stopped_at = Arc::new(AtomicU32::new(0));
+        let peer = MockPeer {
+            events: events.clone(),
+            closed_at: closed_at.clone(),
+        };
+
+        let session_shutdown = Shutdown::new();
+        let mut listener = session_shutdown.listener();
+        {
+            let events = events.clone();
+            let encoder_stopped_at = encoder_stopped_at.clone();
+            tokio::spawn(async move {
+                listener.requested().await;
+                let event = events.fetch_add(1, Ordering::AcqRel) + 1;
+                encoder_stopped_at.store(event, Ordering::Release);
+            });
+        }
+
+        let (_activity, activity_rx) = activity_channel();
+        let timeout = session_timeout(activity_rx, Some(Duration::from_millis(50)), None);
+        let end = wait_for_session_end(peer.is_closed(), pending(), pending(), timeout).await;
+        assert_eq!(end, SessionEnd::Timeout(SessionTimeout::Idle));
+        assert_eq!(events.load(Ordering::Acquire), 0);
+
+        teardown_session(peer, session_shutdown, Duration::from_secs(5)).await;
+        // The encoder threads stop before the peer goes away
+        assert_eq!(encoder_stopped_at.load(Ordering::Acquire), 1);
+        assert_eq!(closed_at.load(Ordering::Acquire), 2);
+    }
+
+    #[tokio::test]
+    async fn first_reason_ends_session() {
+        let end =
+            wait_for_session_end(pending(), async {}, pending(), pending::<SessionTimeout>()).await;
+        assert_eq!(end, SessionEnd::EncoderStopped);
+
+        let end =
+            wait_for_session_end(async {}, pending(), pending(), pending::<SessionTimeout>()).await;
+        assert_eq!(end, SessionEnd::PeerClosed);
+    }
+}
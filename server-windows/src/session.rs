@@ -0,0 +1,110 @@
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::watch, time::Instant};
+
+/// Why `session_timeout` ended a session.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SessionTimeout {
+    /// No input was received for the idle timeout.
+    Idle,
+    /// The session reached its maximum duration.
+    MaxDuration,
+}
+
+/// Signals input activity to `session_timeout`.
+#[derive(Debug, Clone)]
+pub struct ActivitySender {
+    tx: Arc<watch::Sender<()>>,
+}
+
+impl ActivitySender {
+    pub fn notify(&self) {
+        self.tx.send_replace(());
+    }
+}
+
+/// Creates the channel that postpones the idle timeout of a session.
+pub fn activity_channel() -> (ActivitySender, watch::Receiver<()>) {
+    let (tx, rx) = watch::channel(());
+    (ActivitySender { tx: Arc::new(tx) }, rx)
+}
+
+/// Completes when the session has been idle for `idle_timeout` or has lasted for `max_duration`.
+/// A `None` limit is never reached.
+pub async fn session_timeout(
+    mut activity: watch::Receiver<()>,
+    idle_timeout: Option<Duration>,
+    max_duration: Option<Duration>,
+) -> SessionTimeout {
+    let start = Instant::now();
+    let max_deadline = max_duration.map(|duration| start + duration);
+    let mut idle_deadline = idle_timeout.map(|timeout| start + timeout);
+    let mut activity_open = true;
+
+    loop {
+        tokio::select! {
+            _ = sleep_until(max_deadline) => return SessionTimeout::MaxDuration,
+            _ = sleep_until(idle_deadline) => return SessionTimeout::Idle,
+            result = activity.changed(), if activity_open => match result {
+                Ok(_) => idle_deadline = idle_timeout.map(|timeout| Instant::now() + timeout),
+                // The input handler exited so the session can only get more idle
+                Err(_) => activity_open = false,
+            },
+        }
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[tokio::test]
+    async fn idle_session_times_out() {
+        let (_activity, activity_rx) = activity_channel();
+        let start = Instant::now();
+
+        let timeout = session_timeout(activity_rx, Some(TIMEOUT), None).await;
+        assert_eq!(timeout, SessionTimeout::Idle);
+        assert!(start.elapsed() >= TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn activity_postpones_idle_timeout() {
+        let (activity, activity_rx) = activity_channel();
+        let start = Instant::now();
+
+        tokio::spawn(async move {
+            for _ in 0..4 {
+                tokio::time::sleep(TIMEOUT / 2).await;
+                activity.notify();
+            }
+        });
+
+        let timeout = session_timeout(activity_rx, Some(TIMEOUT), Some(TIMEOUT * 10)).await;
+        assert_eq!(timeout, SessionTimeout::Idle);
+        assert!(start.elapsed() >= TIMEOUT * 3);
+    }
+
+    #[tokio::test]
+    async fn max_duration_reached() {
+        let (activity, activity_rx) = activity_channel();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(TIMEOUT / 4).await;
+                activity.notify();
+            }
+        });
+
+        let timeout = session_timeout(activity_rx, Some(TIMEOUT), Some(TIMEOUT * 3)).await;
+        assert_eq!(timeout, SessionTimeout::MaxDuration);
+    }
+}
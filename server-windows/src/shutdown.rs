@@ -0,0 +1,93 @@
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Tells the streaming sessions to stop and waits for them to release their resources.
+pub struct Shutdown {
+    tx: watch::Sender<bool>,
+}
+
+impl Shutdown {
+    /// Creates a new `Shutdown`.
+    pub fn new() -> Shutdown {
+        let (tx, _) = watch::channel(false);
+        Shutdown { tx }
+    }
+
+    /// Returns a listener that is notified when the shutdown is requested. The shutdown is not
+    /// complete until all the listeners have been dropped.
+    pub fn listener(&self) -> ShutdownListener {
+        ShutdownListener {
+            rx: self.tx.subscribe(),
+        }
+    }
+
+    /// Notifies the listeners then waits for them to be dropped. Returns false if some of them are
+    /// still alive after `grace_period`.
+    pub async fn shutdown(self, grace_period: Duration) -> bool {
+        let _ = self.tx.send(true);
+        tokio::time::timeout(grace_period, self.tx.closed())
+            .await
+            .is_ok()
+    }
+}
+
+/// Receiving half of `Shutdown`.
+#[derive(Debug, Clone)]
+pub struct ShutdownListener {
+    rx: watch::Receiver<bool>,
+}
+
+impl ShutdownListener {
+    /// Returns true if the shutdown has been requested.
+    pub fn is_requested(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Waits until the shutdown is requested.
+    pub async fn requested(&mut self) {
+        while !*self.rx.borrow_and_update() {
+            if self.rx.changed().await.is_err() {
+                // `Shutdown` was dropped without being used
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[tokio::test]
+    async fn shutdown_runs_cleanup() {
+        let shutdown = Shutdown::new();
+        let cleaned_up = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..3 {
+            let mut listener = shutdown.listener();
+            let cleaned_up = cleaned_up.clone();
+            tokio::spawn(async move {
+                listener.requested().await;
+                assert!(listener.is_requested());
+                cleaned_up.fetch_add(1, Ordering::AcqRel);
+            });
+        }
+
+        assert!(shutdown.shutdown(Duration::from_secs(5)).await);
+        assert_eq!(cleaned_up.load(Ordering::Acquire), 3);
+    }
+
+    #[tokio::test]
+    async fn shutdown_grace_period() {
+        let shutdown = Shutdown::new();
+        let stuck = shutdown.listener();
+
+        assert!(!stuck.is_requested());
+        assert!(!shutdown.shutdown(Duration::from_millis(10)).await);
+        assert!(stuck.is_requested());
+    }
+}